@@ -0,0 +1,84 @@
+#! /usr/bin/env python3
+"""
+Renders key press statistics of the keyboard controller as an ASCII heat map, shaped like the
+keyboard layout (`KEY_CODES` in `src/custom_key_codes.rs`).
+
+Send 's' over serial to make the controller print its statistics, and save the output, e.g.
+    ./read_output_from_usb > stats.txt &
+    echo -n s > /dev/ttyACM0
+    ./render_heat_map.py stats.txt --counter chatter
+If no file is given, statistics are read from standard input.
+"""
+
+import argparse
+import sys
+
+COUNTERS = ["presses", "chatter", "uncertain", "dropped"]
+SHADES = " .:-=+*#%@"
+CELL_WIDTH = 8
+
+
+def parse_stats(lines):
+    """Return list of rows, where each row is list of (name, counters) tuples."""
+    rows = []
+    inside = False
+    for line in lines:
+        words = line.split()
+        if words == ["STATS", "BEGIN"]:
+            rows = []
+            inside = True
+        elif words == ["STATS", "END"]:
+            inside = False
+        elif inside and words == ["ROW"]:
+            rows.append([])
+        elif inside and len(words) == 2 + len(COUNTERS) and words[0] == "KEY":
+            counters = dict(zip(COUNTERS, map(int, words[2:])))
+            rows[-1].append((words[1], counters))
+    return [row for row in rows if row]
+
+
+def short_name(name):
+    for prefix in ["b::MODIFIERKEY_", "MODIFIERKEY_", "b::KEY_", "KEY_"]:
+        if name.startswith(prefix):
+            return name[len(prefix):]
+    return name
+
+
+def render(rows, counter):
+    maximum = max((c[counter] for row in rows for _, c in row), default=0)
+    print("Heat map of '{}' (max {}), shades: '{}'".format(counter, maximum, SHADES))
+    for row in rows:
+        names = ""
+        heat = ""
+        for name, counters in row:
+            value = counters[counter]
+            level = 0 if maximum == 0 else round(value / maximum * (len(SHADES) - 1))
+            names += short_name(name)[:CELL_WIDTH - 1].ljust(CELL_WIDTH)
+            heat += (SHADES[level] * (CELL_WIDTH - 1)) + " "
+        print(names)
+        print(heat)
+        print(heat)
+    print()
+    top = sorted(((c[counter], name) for row in rows for name, c in row), reverse=True)
+    print("Top keys:")
+    for value, name in top[:10]:
+        if value > 0:
+            print("    {:<24} {}".format(short_name(name), value))
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__,
+                                     formatter_class=argparse.RawDescriptionHelpFormatter)
+    parser.add_argument("file", nargs="?", help="Statistics printed by the controller")
+    parser.add_argument("--counter", choices=COUNTERS, default="presses",
+                        help="Which counter to render (default: presses)")
+    args = parser.parse_args()
+    lines = open(args.file) if args.file else sys.stdin
+    rows = parse_stats(lines)
+    if not rows:
+        sys.exit("No statistics found. They start with line 'STATS BEGIN'.")
+    render(rows, args.counter)
+
+
+if __name__ == "__main__":
+    main()
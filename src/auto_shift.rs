@@ -0,0 +1,207 @@
+//! Auto-shift: key that is held longer than a threshold sends its shifted version, e.g. long
+//! press of '1' types '!'. Threshold is configured separately for letters, numbers and symbols.
+//! Auto-shift is disabled while real modifiers are held.
+//!
+//! Press of auto-shift key is held back until the key is released or the threshold is reached.
+//! Short press is sent as a tap. Long press is sent together with a press of a virtual Shift
+//! key. If another key is pressed meanwhile, the held back press is sent as such.
+
+use teensy3::bindings as b;
+
+use crate::events::{push_warn, EventVec, KeyEvent, AUTO_SHIFT_MATRIX};
+use crate::process_keys::{ExtraKeyInfo, KeyPos};
+use crate::ShortVec;
+use heapless::Vec; // fixed capacity `std::Vec`
+
+/// Auto-shift stage of key events
+#[derive(Debug)]
+pub struct AutoShift {
+    /// Hold time after which letters are shifted (in microseconds), or None to disable
+    pub letters: Option<u32>,
+    /// Hold time after which numbers are shifted (in microseconds), or None to disable
+    pub numbers: Option<u32>,
+    /// Hold time after which symbols are shifted (in microseconds), or None to disable
+    pub symbols: Option<u32>,
+    /// Press that is held back
+    pending: Option<KeyEvent>,
+    /// Releases of short presses, which are sent in the next report
+    tapped: ShortVec<KeyEvent>,
+    /// Pressed keys that are shifted
+    shifted: ShortVec<KeyPos>,
+    /// Pressed modifier keys
+    modifiers: ShortVec<KeyPos>,
+}
+
+impl AutoShift {
+    pub fn new(letters: Option<u32>, numbers: Option<u32>, symbols: Option<u32>) -> AutoShift {
+        return AutoShift {
+            letters,
+            numbers,
+            symbols,
+            pending: None,
+            tapped: Vec::new(),
+            shifted: Vec::new(),
+            modifiers: Vec::new(),
+        };
+    }
+
+    /// Hold time threshold of key in Finnish layout, if key is shifted automatically
+    fn threshold(&self, code: u32) -> Option<u32> {
+        let letters = [b::KEY_LEFT_BRACE, b::KEY_QUOTE, b::KEY_SEMICOLON];
+        let symbols = [b::KEY_MINUS, b::KEY_BACKSLASH, b::KEY_TILDE, b::KEY_NON_US_BS,
+            b::KEY_COMMA, b::KEY_PERIOD, b::KEY_SLASH];
+        if (b::KEY_A..=b::KEY_Z).contains(&code) || letters.contains(&code) {
+            return self.letters;
+        } else if (b::KEY_1..=b::KEY_0).contains(&code) {
+            return self.numbers;
+        } else if symbols.contains(&code) {
+            return self.symbols;
+        } else {
+            return None;
+        }
+    }
+
+    /// Pass event through auto-shift stage. Resulting events are pushed to `out`.
+    pub fn process(&mut self, event: KeyEvent, info: &ExtraKeyInfo, out: &mut EventVec) {
+        let code = event.code.into_inner();
+        let pending_pos = self.pending.map(|p| p.pos);
+        if pending_pos == Some(event.pos) {
+            if event.pressed {
+                // Certainty has changed
+                self.pending = Some(event);
+            } else {
+                // Short press
+                push_warn(out, self.pending.take().unwrap());
+                self.tapped.push(event).unwrap_or(());
+            }
+            return;
+        }
+        if !event.pressed {
+            push_warn(out, event);
+            if let Some(i) = self.modifiers.iter().position(|&pos| pos == event.pos) {
+                self.modifiers.swap_remove(i);
+            }
+            if let Some(i) = self.shifted.iter().position(|&pos| pos == event.pos) {
+                self.shifted.swap_remove(i);
+                if self.shifted.is_empty() {
+                    push_warn(out, self.shift_event(false, event.time));
+                }
+            }
+            return;
+        }
+        // Another key is pressed, so held back press is sent as such
+        if let Some(pending) = self.pending.take() {
+            push_warn(out, pending);
+        }
+        let is_modifier = info.is_modifier(code);
+        if is_modifier {
+            self.modifiers.push(event.pos).unwrap_or(());
+        }
+        let is_certain = event.code.into_option().is_some();
+        let can_shift = self.modifiers.is_empty() && self.shifted.is_empty() && is_certain;
+        if can_shift && self.threshold(code).is_some() {
+            self.pending = Some(event);
+        } else {
+            push_warn(out, event);
+        }
+    }
+
+    /// Release taps of the previous report, and shift the held back press if the threshold is
+    /// reached. This should be called once per report, before `process`.
+    /// # Arguments
+    /// * `now` Current time (in microseconds)
+    /// * `out` Resulting events are pushed here
+    pub fn update(&mut self, now: u32, out: &mut EventVec) {
+        self.tapped.iter().for_each(|&event| push_warn(out, event));
+        self.tapped.clear();
+        if let Some(pending) = self.pending {
+            let threshold = self.threshold(pending.code.into_inner()).unwrap_or(0);
+            if now.wrapping_sub(pending.time) >= threshold {
+                push_warn(out, self.shift_event(true, now));
+                push_warn(out, pending);
+                self.shifted.push(pending.pos).unwrap_or(());
+                self.pending = None;
+            }
+        }
+    }
+
+    fn shift_event(&self, pressed: bool, time: u32) -> KeyEvent {
+        let pos = KeyPos::new(AUTO_SHIFT_MATRIX, 0, 0);
+        return KeyEvent::generated(pos, b::MODIFIERKEY_LEFT_SHIFT, pressed, time);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::testing::{self, key_event};
+    use crate::process_keys::KeyCode;
+
+    const THRESHOLD: u32 = 200_000;
+    const SHIFT: u32 = b::MODIFIERKEY_LEFT_SHIFT;
+
+    /// Feed timed events, calling `update` every millisecond like the report stage does, and
+    /// return the resulting (key code, pressed) pairs. Runs one report past the last event, so
+    /// that taps are released.
+    fn run(events: &[KeyEvent]) -> std::vec::Vec<(u32, bool)> {
+        let info = crate::custom_key_codes::extra_information_about_key_codes();
+        let mut auto_shift = AutoShift::new(Some(THRESHOLD), None, None);
+        let end = events.last().map_or(0, |e| e.time) + 1000;
+        let out = testing::run(&mut auto_shift, events, end,
+                               |a, now, out| a.update(now, out),
+                               |a, e, out| a.process(e, &info, out));
+        return out.iter().map(|e| (e.code.into_inner(), e.pressed)).collect();
+    }
+
+    /// Press and release of key
+    fn hold(code: u32, start: u32, end: u32) -> [KeyEvent; 2] {
+        return [key_event(code, true, start), key_event(code, false, end)];
+    }
+
+    #[test]
+    fn short_press_is_tap() {
+        assert_eq!(run(&hold(b::KEY_A, 0, THRESHOLD - 1000)),
+                   [(b::KEY_A, true), (b::KEY_A, false)]);
+    }
+
+    #[test]
+    fn press_of_threshold_is_shifted() {
+        assert_eq!(run(&hold(b::KEY_A, 0, THRESHOLD)),
+                   [(SHIFT, true), (b::KEY_A, true), (b::KEY_A, false), (SHIFT, false)]);
+    }
+
+    #[test]
+    fn other_keys_are_not_held_back() {
+        assert_eq!(run(&hold(b::KEY_1, 0, THRESHOLD + 100_000)),
+                   [(b::KEY_1, true), (b::KEY_1, false)]);
+    }
+
+    #[test]
+    fn rolled_key_sends_held_back_press() {
+        let events = [
+            key_event(b::KEY_A, true, 0), key_event(b::KEY_S, true, 50_000),
+            key_event(b::KEY_A, false, 60_000), key_event(b::KEY_S, false, 100_000),
+        ];
+        assert_eq!(run(&events), [
+            (b::KEY_A, true), (b::KEY_A, false), (b::KEY_S, true), (b::KEY_S, false),
+        ]);
+    }
+
+    #[test]
+    fn modifier_disables_auto_shift() {
+        let ctrl = b::MODIFIERKEY_LEFT_CTRL;
+        let events = [
+            key_event(ctrl, true, 0), key_event(b::KEY_A, true, 10_000),
+            key_event(b::KEY_A, false, 2 * THRESHOLD), key_event(ctrl, false, 3 * THRESHOLD),
+        ];
+        assert_eq!(run(&events),
+                   [(ctrl, true), (b::KEY_A, true), (b::KEY_A, false), (ctrl, false)]);
+    }
+
+    #[test]
+    fn uncertain_press_is_not_held_back() {
+        let mut events = hold(b::KEY_A, 0, 2 * THRESHOLD);
+        events[0].code = KeyCode::Uncertain(b::KEY_A);
+        assert_eq!(run(&events), [(b::KEY_A, true), (b::KEY_A, false)]);
+    }
+}
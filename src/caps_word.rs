@@ -0,0 +1,218 @@
+//! Caps Word mode. When it is on, letters are shifted and '-' turns into '_', so that words like
+//! `MAX_VALUE` can be typed without holding Shift. Caps Word ends when a key that does not belong
+//! to a word is pressed, e.g. space, or after `timeout`. The Caps Lock state of the host is not
+//! touched, but shifted keys are wrapped in press and release of a virtual Shift key.
+//!
+//! Caps Word is toggled with `CAPS_WORD_KEY`.
+
+use teensy3::bindings as b;
+
+use crate::events::{push_warn, EventVec, KeyEvent, CAPS_WORD_MATRIX};
+use crate::process_keys::{ExtraKeyInfo, KeyPos};
+use crate::ShortVec;
+use heapless::Vec; // fixed capacity `std::Vec`
+
+/// Key code that toggles Caps Word
+pub const CAPS_WORD_KEY: u32 = 0xEC00;
+
+/// Keys that are shifted: letters of Finnish layout, and '-' (which is on the slash key)
+fn is_shifted(code: u32) -> bool {
+    return (b::KEY_A..=b::KEY_Z).contains(&code)
+        || [b::KEY_LEFT_BRACE, b::KEY_QUOTE, b::KEY_SEMICOLON, b::KEY_SLASH].contains(&code);
+}
+
+/// Keys that continue word without shift
+fn continues_word(code: u32) -> bool {
+    return (b::KEY_1..=b::KEY_0).contains(&code)
+        || [b::KEY_BACKSPACE, b::KEY_DELETE].contains(&code);
+}
+
+/// Caps Word stage of key events
+#[derive(Debug)]
+pub struct CapsWord {
+    /// Caps Word ends if no key is pressed within this time (in microseconds)
+    pub timeout: u32,
+    on: bool,
+    /// Time of the previous key press (in microseconds)
+    since: u32,
+    /// Pressed keys that are shifted
+    shifted: ShortVec<KeyPos>,
+    /// Whether virtual Shift is pressed
+    shift_pressed: bool,
+    /// Position of toggle key, so that its release is swallowed too
+    toggle_pos: Option<KeyPos>,
+}
+
+impl CapsWord {
+    pub fn new(timeout: u32) -> CapsWord {
+        return CapsWord {
+            timeout,
+            on: false,
+            since: 0,
+            shifted: Vec::new(),
+            shift_pressed: false,
+            toggle_pos: None,
+        };
+    }
+
+    /// Pass event through Caps Word stage. Resulting events are pushed to `out`.
+    pub fn process(&mut self, event: KeyEvent, info: &ExtraKeyInfo, out: &mut EventVec) {
+        let code = event.code.into_inner();
+        if code == CAPS_WORD_KEY {
+            if event.pressed && self.toggle_pos != Some(event.pos) {
+                self.toggle_pos = Some(event.pos);
+                self.on = !self.on;
+                self.since = event.time;
+                println!("Caps Word {}", if self.on { "on" } else { "off" });
+            } else if !event.pressed {
+                self.toggle_pos = None;
+            }
+            return;
+        }
+        if !event.pressed {
+            push_warn(out, event);
+            if let Some(i) = self.shifted.iter().position(|&pos| pos == event.pos) {
+                self.shifted.swap_remove(i);
+                if self.shifted.is_empty() {
+                    self.set_shift(false, event.time, out);
+                }
+            }
+            return;
+        }
+        let is_modifier = info.is_modifier(code);
+        if !self.on || is_modifier || self.shifted.contains(&event.pos) {
+            push_warn(out, event);
+            return;
+        }
+        self.since = event.time;
+        if is_shifted(code) {
+            self.set_shift(true, event.time, out);
+            self.shifted.push(event.pos).unwrap_or(());
+        } else {
+            // Shift must not affect other keys
+            self.set_shift(false, event.time, out);
+            if !continues_word(code) {
+                self.on = false;
+                println!("Caps Word off");
+            }
+        }
+        push_warn(out, event);
+    }
+
+    /// End Caps Word if its time is up
+    /// # Arguments
+    /// * `now` Current time (in microseconds)
+    pub fn update(&mut self, now: u32) {
+        if self.on && self.shifted.is_empty() && now.wrapping_sub(self.since) >= self.timeout {
+            self.on = false;
+            println!("Caps Word off");
+        }
+    }
+
+    fn set_shift(&mut self, pressed: bool, time: u32, out: &mut EventVec) {
+        if self.shift_pressed == pressed {
+            return;
+        }
+        self.shift_pressed = pressed;
+        let pos = KeyPos::new(CAPS_WORD_MATRIX, 0, 0);
+        push_warn(out, KeyEvent::generated(pos, b::MODIFIERKEY_LEFT_SHIFT, pressed, time));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::testing::{self, key_event};
+
+    const TIMEOUT: u32 = 5_000_000;
+    const SHIFT: u32 = b::MODIFIERKEY_LEFT_SHIFT;
+
+    /// Tap keys one after another, one millisecond apart, and return the resulting (key code,
+    /// pressed) pairs
+    fn tap(caps: &mut CapsWord, codes: &[u32], start: u32) -> std::vec::Vec<(u32, bool)> {
+        let info = crate::custom_key_codes::extra_information_about_key_codes();
+        let events: std::vec::Vec<_> = codes.iter().enumerate()
+            .map(|(i, &code)| (code, start + 1000 * i as u32))
+            .flat_map(|(code, t)| vec![key_event(code, true, t), key_event(code, false, t + 500)])
+            .collect();
+        let out = testing::run(caps, &events, 0,
+                               |c, now, _| c.update(now), |c, e, out| c.process(e, &info, out));
+        return out.iter().map(|e| (e.code.into_inner(), e.pressed)).collect();
+    }
+
+    fn caps_word_on() -> CapsWord {
+        let mut caps = CapsWord::new(TIMEOUT);
+        assert!(tap(&mut caps, &[CAPS_WORD_KEY], 0).is_empty());
+        return caps;
+    }
+
+    #[test]
+    fn letters_are_shifted() {
+        let mut caps = caps_word_on();
+        assert_eq!(tap(&mut caps, &[b::KEY_A, b::KEY_B], 1000), [
+            (SHIFT, true), (b::KEY_A, true), (b::KEY_A, false), (SHIFT, false),
+            (SHIFT, true), (b::KEY_B, true), (b::KEY_B, false), (SHIFT, false),
+        ]);
+    }
+
+    #[test]
+    fn minus_turns_into_underscore() {
+        // '-' is on the slash key of Finnish layout, and Shift makes it '_'
+        let mut caps = caps_word_on();
+        assert_eq!(tap(&mut caps, &[b::KEY_SLASH], 1000),
+                   [(SHIFT, true), (b::KEY_SLASH, true), (b::KEY_SLASH, false), (SHIFT, false)]);
+    }
+
+    #[test]
+    fn digits_and_backspace_continue_word_without_shift() {
+        let mut caps = caps_word_on();
+        assert_eq!(tap(&mut caps, &[b::KEY_1, b::KEY_BACKSPACE], 1000),
+                   [(b::KEY_1, true), (b::KEY_1, false),
+                    (b::KEY_BACKSPACE, true), (b::KEY_BACKSPACE, false)]);
+        assert_eq!(tap(&mut caps, &[b::KEY_A], 3000).first(), Some(&(SHIFT, true)));
+    }
+
+    #[test]
+    fn space_breaks_word() {
+        let mut caps = caps_word_on();
+        assert_eq!(tap(&mut caps, &[b::KEY_SPACE, b::KEY_A], 1000),
+                   [(b::KEY_SPACE, true), (b::KEY_SPACE, false),
+                    (b::KEY_A, true), (b::KEY_A, false)]);
+    }
+
+    #[test]
+    fn word_break_releases_shift_of_held_letter() {
+        let info = crate::custom_key_codes::extra_information_about_key_codes();
+        let mut caps = caps_word_on();
+        let mut out = EventVec::new();
+        caps.process(key_event(b::KEY_A, true, 1000), &info, &mut out);
+        caps.process(key_event(b::KEY_SPACE, true, 2000), &info, &mut out);
+        let out: std::vec::Vec<_> = out.iter().map(|e| (e.code.into_inner(), e.pressed)).collect();
+        assert_eq!(out, [(SHIFT, true), (b::KEY_A, true), (SHIFT, false), (b::KEY_SPACE, true)]);
+    }
+
+    #[test]
+    fn modifiers_pass_through_and_keep_word() {
+        let mut caps = caps_word_on();
+        assert_eq!(tap(&mut caps, &[b::MODIFIERKEY_LEFT_CTRL], 1000),
+                   [(b::MODIFIERKEY_LEFT_CTRL, true), (b::MODIFIERKEY_LEFT_CTRL, false)]);
+        assert_eq!(tap(&mut caps, &[b::KEY_A], 2000).first(), Some(&(SHIFT, true)));
+    }
+
+    #[test]
+    fn toggle_key_ends_word() {
+        let mut caps = caps_word_on();
+        assert!(tap(&mut caps, &[CAPS_WORD_KEY], 1000).is_empty());
+        assert_eq!(tap(&mut caps, &[b::KEY_A], 2000), [(b::KEY_A, true), (b::KEY_A, false)]);
+    }
+
+    #[test]
+    fn word_ends_after_timeout() {
+        let mut caps = caps_word_on();
+        assert_eq!(tap(&mut caps, &[b::KEY_A], TIMEOUT - 1000).first(), Some(&(SHIFT, true)));
+        // Timeout is counted from the previous key press
+        assert_eq!(tap(&mut caps, &[b::KEY_A], 2 * TIMEOUT - 2000).first(), Some(&(SHIFT, true)));
+        assert_eq!(tap(&mut caps, &[b::KEY_A], 3 * TIMEOUT - 2000),
+                   [(b::KEY_A, true), (b::KEY_A, false)]);
+    }
+}
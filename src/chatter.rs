@@ -0,0 +1,220 @@
+//! Detection of chattering keys. Aging key pads may have individual keys that double-fire, because
+//! their contacts bounce longer than the debounce time. `ChatterTuner` counts presses and bounces
+//! of each key, and if some key bounces too often, its debounce time is raised. This does not
+//! touch any hardware: the bounces are reported by `Debouncer`, and the new debounce times are
+//! given back to it.
+
+use heapless::Vec; // fixed capacity `std::Vec`
+use typenum::U96 as TrackedCap; // Maximum number of tracked keys
+
+use crate::process_keys::KeyPos;
+
+/// Presses and bounces of one key since the last evaluation
+#[derive(Debug, Copy, Clone)]
+struct ChatterCount {
+    presses: u16,
+    bounces: u16,
+}
+
+/// Tuner that raises debounce time of keys that bounce too often.
+#[derive(Debug)]
+pub struct ChatterTuner {
+    /// Number of presses after which the bounce rate of key is evaluated
+    pub window: u16,
+    /// Key is considered chattering if it bounces on at least this many percent of presses
+    pub threshold_percent: u16,
+    /// How much debounce time is raised at once (in microseconds)
+    pub step: u32,
+    /// Debounce time is never raised above this (in microseconds)
+    pub max_debounce_time: u32,
+    keys: Vec<(KeyPos, ChatterCount), TrackedCap>,
+}
+
+impl ChatterTuner {
+    pub fn new(window: u16, threshold_percent: u16, step: u32, max_debounce_time: u32)
+        -> ChatterTuner
+    {
+        ChatterTuner { window, threshold_percent, step, max_debounce_time, keys: Vec::new() }
+    }
+
+    fn count(&mut self, pos: KeyPos) -> Option<&mut ChatterCount> {
+        let idx = match self.keys.iter().position(|&(p, _)| p == pos) {
+            Some(idx) => idx,
+            None => {
+                self.keys.push((pos, ChatterCount { presses: 0, bounces: 0 })).ok()?;
+                self.keys.len() - 1
+            }
+        };
+        return Some(&mut self.keys[idx].1);
+    }
+
+    /// Count one bounce of key
+    pub fn record_bounce(&mut self, pos: KeyPos) {
+        if let Some(c) = self.count(pos) {
+            c.bounces = c.bounces.saturating_add(1);
+        }
+    }
+
+    /// Count one press of key. After every `window` presses the bounce rate is evaluated, and if
+    /// it exceeds the threshold, the new raised debounce time is returned. Returns None if nothing
+    /// needs to be changed, or if debounce time is already at maximum.
+    /// # Arguments
+    /// * `pos`           Position of the pressed key
+    /// * `debounce_time` Current debounce time of the key (in microseconds)
+    pub fn record_press(&mut self, pos: KeyPos, debounce_time: u32) -> Option<u32> {
+        let (window, threshold_percent) = (self.window, self.threshold_percent);
+        let c = self.count(pos)?;
+        c.presses += 1;
+        if c.presses < window {
+            return None;
+        }
+        let chattering = c.bounces as u32 * 100 >= threshold_percent as u32 * c.presses as u32;
+        *c = ChatterCount { presses: 0, bounces: 0 };
+        if chattering && debounce_time < self.max_debounce_time {
+            return Some(u32::min(debounce_time + self.step, self.max_debounce_time));
+        } else {
+            return None;
+        }
+    }
+
+    /// Forget all counts
+    pub fn reset(&mut self) {
+        self.keys.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventQueue;
+    use crate::process_keys::{Debouncer, KeyCode};
+    use crate::ShortVec;
+
+    const KEY: KeyPos = KeyPos { matrix: 0, row: 1, col: 2 };
+
+    #[test]
+    fn clean_key_is_not_tuned() {
+        let mut tuner = ChatterTuner::new(20, 10, 10_000, 80_000);
+        for _ in 0..100 {
+            assert_eq!(tuner.record_press(KEY, 30_000), None);
+        }
+    }
+
+    #[test]
+    fn chattering_key_is_tuned() {
+        let mut tuner = ChatterTuner::new(20, 10, 10_000, 80_000);
+        for i in 1..20 {
+            if i % 10 == 0 {
+                tuner.record_bounce(KEY);
+            }
+            assert_eq!(tuner.record_press(KEY, 30_000), None);
+        }
+        tuner.record_bounce(KEY);
+        assert_eq!(tuner.record_press(KEY, 30_000), Some(40_000));
+        // Counts start again after evaluation
+        for _ in 0..20 {
+            assert_eq!(tuner.record_press(KEY, 40_000), None);
+        }
+    }
+
+    #[test]
+    fn bounce_rate_below_threshold_is_not_tuned() {
+        let mut tuner = ChatterTuner::new(20, 10, 10_000, 80_000);
+        tuner.record_bounce(KEY);
+        for _ in 0..20 {
+            assert_eq!(tuner.record_press(KEY, 30_000), None);
+        }
+    }
+
+    #[test]
+    fn tuning_stops_at_maximum() {
+        let mut tuner = ChatterTuner::new(1, 10, 10_000, 35_000);
+        tuner.record_bounce(KEY);
+        assert_eq!(tuner.record_press(KEY, 30_000), Some(35_000));
+        tuner.record_bounce(KEY);
+        assert_eq!(tuner.record_press(KEY, 35_000), None);
+    }
+
+    /// Scan where `KEY` is pressed or not
+    fn scan(pressed: bool) -> Option<ShortVec<(KeyPos, KeyCode<u32>)>> {
+        return if pressed {
+            Some([(KEY, KeyCode::Certain(0xF004))].iter().copied().collect())
+        } else {
+            None
+        };
+    }
+
+    /// Feed trace of scans (one per millisecond) to debouncer and tuner, and return number of
+    /// registered presses and the last tuned debounce time
+    fn run_trace(trace: &[bool], debouncer: &mut Debouncer, tuner: &mut ChatterTuner)
+        -> (u32, Option<u32>)
+    {
+        let mut events = EventQueue::new();
+        let mut presses = 0;
+        let mut tuned = None;
+        for (i, &pressed) in trace.iter().enumerate() {
+            let bounced = debouncer.debounce(scan(pressed), i as u32 * 1000, &mut events);
+            bounced.iter().for_each(|&pos| tuner.record_bounce(pos));
+            while let Some(event) = events.dequeue() {
+                if event.pressed {
+                    presses += 1;
+                    let old_time = debouncer.debounce_time_of(event.pos);
+                    if let Some(new_time) = tuner.record_press(event.pos, old_time) {
+                        debouncer.set_debounce_time(event.pos, new_time).unwrap();
+                        tuned = Some(new_time);
+                    }
+                }
+            }
+        }
+        return (presses, tuned);
+    }
+
+    /// Trace of `n` presses, where contacts bounce `bounce_at` ms after release if given
+    fn noisy_trace(n: usize, bounce_at: Option<usize>) -> std::vec::Vec<bool> {
+        let mut trace = std::vec::Vec::new();
+        for _ in 0..n {
+            trace.extend_from_slice(&[true; 10]);
+            let mut released = [false; 50];
+            if let Some(t) = bounce_at {
+                released[t] = true;
+            }
+            trace.extend_from_slice(&released);
+        }
+        return trace;
+    }
+
+    #[test]
+    fn noisy_trace_raises_debounce_time() {
+        let mut debouncer = Debouncer::new(5_000);
+        let mut tuner = ChatterTuner::new(20, 10, 10_000, 80_000);
+        // Bounce 3 ms after release is caught by debouncing, but counted as chatter
+        let (presses, tuned) = run_trace(&noisy_trace(20, Some(3)), &mut debouncer, &mut tuner);
+        assert_eq!(presses, 20);
+        assert_eq!(tuned, Some(15_000));
+        assert_eq!(debouncer.debounce_time_of(KEY), 15_000);
+    }
+
+    #[test]
+    fn clean_trace_keeps_debounce_time() {
+        let mut debouncer = Debouncer::new(5_000);
+        let mut tuner = ChatterTuner::new(20, 10, 10_000, 80_000);
+        let (presses, tuned) = run_trace(&noisy_trace(40, None), &mut debouncer, &mut tuner);
+        assert_eq!(presses, 40);
+        assert_eq!(tuned, None);
+        assert_eq!(debouncer.debounce_time_of(KEY), 5_000);
+    }
+
+    #[test]
+    fn raised_debounce_time_filters_longer_bounces() {
+        let mut debouncer = Debouncer::new(5_000);
+        let mut tuner = ChatterTuner::new(20, 10, 10_000, 80_000);
+        // Bounce 8 ms after release double-fires with 5 ms debounce time
+        let (presses, tuned) = run_trace(&noisy_trace(20, Some(8)), &mut debouncer, &mut tuner);
+        assert_eq!(presses, 40);
+        assert_eq!(tuned, None);
+        // After tuning to 15 ms, the same bounce is filtered
+        debouncer.set_debounce_time(KEY, 15_000).unwrap();
+        let (presses, _) = run_trace(&noisy_trace(20, Some(8)), &mut debouncer, &mut tuner);
+        assert_eq!(presses, 20);
+    }
+}
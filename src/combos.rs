@@ -0,0 +1,288 @@
+//! Combos, i.e. chords of keys that emit a different key. For example, if J and K are pressed
+//! at the same time, Esc is sent instead of them. This stage sits between the event queue and
+//! `categorize_key_presses`, and it holds back presses of combo keys for a short window.
+//!
+//! Resolution rules:
+//! * Presses are buffered as long as the buffered keys are a part of some combo. If they match a
+//!   combo exactly, and no larger combo is possible, the combo fires immediately.
+//! * If the window expires, or some key is released, or some other key is pressed, the combo
+//!   that exactly matches the buffered keys fires. If there is no such combo, the buffered
+//!   presses are passed on as such, in the original order.
+//! * Combo key is released when the first of its keys is released. Releases of the rest of its
+//!   keys are swallowed.
+//! * Uncertain presses never fire combos.
+
+use heapless::Vec; // fixed capacity `std::Vec`
+use typenum::U16 as CombosCap; // Maximum number of combos
+use typenum::U8 as ActiveCap; // Maximum number of combos that are pressed simultaneously
+
+use crate::events::{push_warn, EventVec, KeyEvent, COMBO_MATRIX};
+use crate::process_keys::KeyPos;
+use crate::ShortVec;
+
+#[derive(Debug)]
+struct Combo {
+    keys: ShortVec<KeyPos>,
+    code: u32,
+}
+
+/// Combo that is pressed
+#[derive(Debug)]
+struct ActiveCombo {
+    idx: usize,
+    /// Keys of combo that are still held
+    held: ShortVec<KeyPos>,
+    /// False after the first key of combo is released
+    pressed: bool,
+}
+
+/// Combo stage of key events
+#[derive(Debug)]
+pub struct Combos {
+    /// Keys of combo must be pressed within this time (in microseconds)
+    pub window: u32,
+    combos: Vec<Combo, CombosCap>,
+    /// Held back presses
+    buffer: ShortVec<KeyEvent>,
+    active: Vec<ActiveCombo, ActiveCap>,
+}
+
+impl Combos {
+    pub fn new(window: u32) -> Combos {
+        return Combos { window, combos: Vec::new(), buffer: Vec::new(), active: Vec::new() };
+    }
+
+    /// Add combo of keys that emits `code`. Returns `Err` if there are too many combos.
+    pub fn add(&mut self, keys: &[KeyPos], code: u32) -> Result<(), ()> {
+        let keys = Vec::from_slice(keys)?;
+        return self.combos.push(Combo { keys, code }).map_err(|_| ());
+    }
+
+    /// Virtual position of n:th combo key. It does not belong to any real key matrix.
+    pub fn position_of(idx: usize) -> KeyPos {
+        return KeyPos::new(COMBO_MATRIX, 0, idx);
+    }
+
+    /// Pass event through combo stage. Resulting events are pushed to `out`.
+    pub fn process(&mut self, event: KeyEvent, out: &mut EventVec) {
+        // Key of pressed combo
+        if let Some(a) = self.active.iter().position(|a| a.held.contains(&event.pos)) {
+            if !event.pressed {
+                self.release_active(a, &event, out);
+            }
+            return;
+        }
+        // Buffered key
+        if let Some(i) = self.buffer.iter().position(|e| e.pos == event.pos) {
+            if event.pressed {
+                // Certainty has changed
+                self.buffer[i].code = event.code;
+            } else {
+                self.resolve(out);
+                self.process(event, out);
+            }
+            return;
+        }
+        if !event.pressed {
+            push_warn(out, event);
+            return;
+        }
+        let is_certain = event.code.into_option().is_some();
+        let mut candidate: ShortVec<KeyPos> = self.buffer.iter().map(|e| e.pos).collect();
+        if is_certain && candidate.push(event.pos).is_ok() && self.is_part_of_combo(&candidate) {
+            push_warn(&mut self.buffer, event);
+            if self.exact_combo().is_some() && !self.larger_combo_possible(&candidate) {
+                self.resolve(out);
+            }
+        } else if !self.buffer.is_empty() {
+            self.resolve(out);
+            self.process(event, out);
+        } else {
+            push_warn(out, event);
+        }
+    }
+
+    /// Resolve buffered presses if the window has expired
+    /// # Arguments
+    /// * `now` Current time (in microseconds)
+    /// * `out` Resulting events are pushed here
+    pub fn update(&mut self, now: u32, out: &mut EventVec) {
+        if let Some(first) = self.buffer.first() {
+            if now.wrapping_sub(first.time) >= self.window {
+                self.resolve(out);
+            }
+        }
+    }
+
+    /// Fire the combo that matches buffered keys, or pass the buffered presses on
+    fn resolve(&mut self, out: &mut EventVec) {
+        match self.exact_combo() {
+            Some(idx) => {
+                let first = self.buffer[0];
+                let last = self.buffer[self.buffer.len() - 1];
+                let combo = KeyEvent::generated(
+                    Combos::position_of(idx), self.combos[idx].code, true, first.time);
+                push_warn(out, KeyEvent { debounced: last.debounced, ..combo });
+                let held = self.buffer.iter().map(|e| e.pos).collect();
+                if self.active.push(ActiveCombo { idx, held, pressed: true }).is_err() {
+                    println!("Warning! Too many combos pressed simultaneously.");
+                }
+            }
+            None => self.buffer.iter().for_each(|&e| push_warn(out, e)),
+        }
+        self.buffer.clear();
+    }
+
+    fn release_active(&mut self, a: usize, event: &KeyEvent, out: &mut EventVec) {
+        let active = &mut self.active[a];
+        if active.pressed {
+            active.pressed = false;
+            push_warn(out, KeyEvent { pos: Combos::position_of(active.idx), ..*event });
+        }
+        if let Some(i) = active.held.iter().position(|&pos| pos == event.pos) {
+            active.held.swap_remove(i);
+        }
+        if active.held.is_empty() {
+            self.active.swap_remove(a);
+        }
+    }
+
+    /// Index of combo whose keys are exactly the buffered keys, if all of them are certain
+    fn exact_combo(&self) -> Option<usize> {
+        if self.buffer.iter().any(|e| e.code.into_option().is_none()) {
+            return None;
+        }
+        return self.combos.iter().position(|c| {
+            c.keys.len() == self.buffer.len()
+                && self.buffer.iter().all(|e| c.keys.contains(&e.pos))
+        });
+    }
+
+    /// Whether keys are a part of some combo
+    fn is_part_of_combo(&self, keys: &ShortVec<KeyPos>) -> bool {
+        return self.combos.iter().any(|c| keys.iter().all(|pos| c.keys.contains(pos)));
+    }
+
+    /// Whether keys are a part of some combo that has also other keys
+    fn larger_combo_possible(&self, keys: &ShortVec<KeyPos>) -> bool {
+        return self.combos.iter()
+            .any(|c| c.keys.len() > keys.len() && keys.iter().all(|pos| c.keys.contains(pos)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::testing::{self, event};
+    use crate::process_keys::KeyCode;
+
+    const J: KeyPos = KeyPos { matrix: 0, row: 0, col: 0 };
+    const K: KeyPos = KeyPos { matrix: 0, row: 0, col: 1 };
+    const L: KeyPos = KeyPos { matrix: 0, row: 0, col: 2 };
+    const X: KeyPos = KeyPos { matrix: 0, row: 1, col: 0 };
+    const ESC: u32 = 0xF029;
+    const TAB: u32 = 0xF02B;
+    const WINDOW: u32 = 50_000;
+
+    /// Process events without expiring the window, and return the resulting (position, pressed,
+    /// time) tuples
+    fn run(combos: &mut Combos, events: &[KeyEvent]) -> std::vec::Vec<(KeyPos, bool, u32)> {
+        let out = testing::run(combos, events, 0, |_, _, _| {}, |c, e, out| c.process(e, out));
+        return out.iter().map(|e| (e.pos, e.pressed, e.time)).collect();
+    }
+
+    fn combos(list: &[(&[KeyPos], u32)]) -> Combos {
+        let mut combos = Combos::new(WINDOW);
+        list.iter().for_each(|&(keys, code)| combos.add(keys, code).unwrap());
+        return combos;
+    }
+
+    #[test]
+    fn combo_fires_when_keys_pressed_within_window() {
+        let mut c = combos(&[(&[J, K], ESC)]);
+        let out = run(&mut c, &[event(J, true, 0), event(K, true, 10_000)]);
+        assert_eq!(out, [(Combos::position_of(0), true, 0)]);
+    }
+
+    #[test]
+    fn combo_code_is_emitted() {
+        let mut c = combos(&[(&[J, K], ESC)]);
+        let mut out = EventVec::new();
+        c.process(event(J, true, 0), &mut out);
+        c.process(event(K, true, 10_000), &mut out);
+        assert_eq!(out[0].code, KeyCode::Certain(ESC));
+    }
+
+    #[test]
+    fn window_timeout_passes_presses_on() {
+        let mut c = combos(&[(&[J, K], ESC)]);
+        assert_eq!(run(&mut c, &[event(J, true, 0)]), []);
+        let mut out = EventVec::new();
+        c.update(WINDOW - 1, &mut out);
+        assert!(out.is_empty());
+        c.update(WINDOW, &mut out);
+        assert_eq!(out.len(), 1);
+        assert_eq!((out[0].pos, out[0].pressed, out[0].time), (J, true, 0));
+        // K after the window is a normal press
+        assert_eq!(run(&mut c, &[event(K, true, WINDOW + 10_000)]), []);
+        let mut out = EventVec::new();
+        c.update(2 * WINDOW + 10_000, &mut out);
+        assert_eq!(out[0].pos, K);
+    }
+
+    #[test]
+    fn combo_is_released_with_first_released_key() {
+        for &(first, second) in [(J, K), (K, J)].iter() {
+            let mut c = combos(&[(&[J, K], ESC)]);
+            let out = run(&mut c, &[
+                event(J, true, 0), event(K, true, 10_000),
+                event(first, false, 100_000), event(second, false, 120_000),
+            ]);
+            assert_eq!(out, [
+                (Combos::position_of(0), true, 0), (Combos::position_of(0), false, 100_000),
+            ]);
+        }
+    }
+
+    #[test]
+    fn release_before_combo_completes_passes_key_on() {
+        let mut c = combos(&[(&[J, K], ESC)]);
+        let out = run(&mut c, &[event(J, true, 0), event(J, false, 20_000)]);
+        assert_eq!(out, [(J, true, 0), (J, false, 20_000)]);
+    }
+
+    #[test]
+    fn other_key_interrupts_combo() {
+        let mut c = combos(&[(&[J, K], ESC)]);
+        let out = run(&mut c, &[event(J, true, 0), event(X, true, 10_000)]);
+        assert_eq!(out, [(J, true, 0), (X, true, 10_000)]);
+    }
+
+    #[test]
+    fn overlapping_combos_wait_for_larger_one() {
+        let list: &[(&[KeyPos], u32)] = &[(&[J, K], ESC), (&[J, K, L], TAB)];
+        // All three keys fire the larger combo immediately
+        let mut c = combos(list);
+        let out = run(&mut c, &[event(J, true, 0), event(K, true, 5_000), event(L, true, 9_000)]);
+        assert_eq!(out, [(Combos::position_of(1), true, 0)]);
+        // Two keys fire the smaller combo when the window expires
+        let mut c = combos(list);
+        assert_eq!(run(&mut c, &[event(J, true, 0), event(K, true, 5_000)]), []);
+        let mut out = EventVec::new();
+        c.update(WINDOW, &mut out);
+        assert_eq!((out[0].pos, out[0].code), (Combos::position_of(0), KeyCode::Certain(ESC)));
+        // ...or when some other key is pressed
+        let mut c = combos(list);
+        let out = run(&mut c, &[event(J, true, 0), event(K, true, 5_000), event(X, true, 9_000)]);
+        assert_eq!(out, [(Combos::position_of(0), true, 0), (X, true, 9_000)]);
+    }
+
+    #[test]
+    fn uncertain_press_does_not_fire_combo() {
+        let mut c = combos(&[(&[J, K], ESC)]);
+        let mut uncertain = event(K, true, 10_000);
+        uncertain.code = KeyCode::Uncertain(0xF005);
+        let out = run(&mut c, &[event(J, true, 0), uncertain]);
+        assert_eq!(out, [(J, true, 0), (K, true, 10_000)]);
+    }
+}
@@ -0,0 +1,27 @@
+//! Simple serial console. Commands are single characters sent over USB serial, for example with
+//! `echo -n l > /dev/ttyACM0`. The output is printed over the same serial port, and it can be
+//! read with `read_output_from_usb`.
+
+use teensy3::bindings as b;
+
+/// Read one command character from USB serial, if any is available.
+pub fn read_command() -> Option<u8> {
+    let c = unsafe { b::usb_serial_getchar() };
+    return if c >= 0 { Some(c as u8) } else { None };
+}
+
+/// Print list of available commands
+pub fn print_help() {
+    println!("Commands:");
+    println!("    h    Print this help");
+    println!("    m    Toggle input latency measurement");
+    println!("    l    Print input latency statistics");
+    println!("    L    Reset input latency statistics");
+    println!("    s    Print key press statistics");
+    println!("    S    Reset key press statistics");
+    println!("    d    Print debounce times of chattering keys");
+    println!("    D    Reset debounce times of chattering keys");
+    println!("    k    Print stuck keys");
+    println!("    r    Print recorded dynamic macro");
+    println!("    t    Toggle firmware typematic mode");
+}
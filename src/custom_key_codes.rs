@@ -1,14 +1,161 @@
 //! This file contains custom key layout configuration of my keyboard.
 //! This is also good place to see how key matrix recording is done in practise.
 
-use crate::process_keys::{ExtraKeyInfo, KeyMatrix};
+use crate::caps_word::CAPS_WORD_KEY;
+use crate::combos::Combos;
+use crate::key_overrides::{KeyOverride, KeyOverrides};
+use crate::layouts::{layout_key, Layout, Layouts};
+use crate::leader::{Leader, LeaderAction, LEADER_KEY};
+use crate::macros::{macro_key, Macro, MacroStep::*};
+use crate::os_profile::{profile_key, OsProfile, OsProfiles, ProfileMap};
+use crate::process_keys::{Diodes, ExtraKeyInfo, KeyMatrices, KeyMatrix, KeyPos};
 use crate::record_keyboard_matrix::figure_out_key_matrix;
+use crate::stats::KeyStats;
+use crate::tap_dance::TapDances;
+use crate::typematic::{Repeat, Typematic};
 use crate::ShortVec;
 use heapless::Vec;
 use teensy3::{bindings as b, pins::PinRow};
 
 const MODIFIERKEY_FN: u32 = 0xE800;
 
+/// Laptop keyboard pads do not have diodes. (Mechanical keyboards often have.)
+const DIODES: Diodes = Diodes::Absent;
+
+/// Keys that connect single pin to the ground, i.e. are not part of the key matrix. Tuple is
+/// (pin, key code). My keyboard does not have any, but for example a foot pedal on pin 33 would be
+/// `(33, b::KEY_SPACE)`.
+const DIRECT_KEYS: &[(usize, u32)] = &[];
+
+/// Macros, see `macros::MacroStep`. To assign n:th macro to some key, replace the key code with
+/// `macro_key(n)` in `KEY_CODES` or in `DIRECT_KEYS`. Similarly, dynamic macro is recorded with
+/// `dynamic_macro::RECORD_KEY` and played with `dynamic_macro::PLAY_KEY`.
+const MACROS: &[Macro] = &[
+    // Greeting
+    &[Type("Hyvää päivää! Kahvi maksaa 2 €.\n")],
+    // Select all and copy
+    &[Press(b::MODIFIERKEY_LEFT_CTRL), Press(b::KEY_A), Release(b::KEY_A), Delay(50),
+        Press(b::KEY_C), Release(b::KEY_C), Release(b::MODIFIERKEY_LEFT_CTRL)],
+];
+
+/// Combos, i.e. keys that are pressed at the same time to emit another key, see `combos`.
+/// For example `(&[b::KEY_J, b::KEY_K], b::KEY_ESC)` sends Esc when J and K are pressed together.
+/// The keys are located in the key matrix by their key codes.
+const COMBOS: &[(&[u32], u32)] = &[
+    // Home and End together are the leader key, see `LEADER_SEQUENCES`
+    (&[b::KEY_HOME, b::KEY_END], LEADER_KEY),
+    // (&[b::KEY_J, b::KEY_K], b::KEY_ESC),
+    // (&[b::KEY_F, b::KEY_D], b::KEY_TAB),
+];
+
+/// Tap dance keys, see `tap_dance`. Tuple is (key, actions of one, two, three... taps, action of
+/// hold). For example `(b::KEY_ESC, &[b::KEY_ESC, b::KEY_CAPS_LOCK], None)` makes double tap of
+/// Esc to be Caps Lock. Text actions, like ':' on Finnish layout, can be done with `macro_key`.
+const TAP_DANCES: &[(u32, &[u32], Option<u32>)] = &[
+    // (b::KEY_ESC, &[b::KEY_ESC, b::KEY_CAPS_LOCK], None),
+    // (b::KEY_COMMA, &[b::KEY_COMMA, macro_key(2)], Some(b::MODIFIERKEY_RIGHT_CTRL)),
+];
+
+/// Leader key sequences, see `leader`. Leader key `LEADER_KEY` is the Home + End combo above.
+const LEADER_SEQUENCES: &[(&[u32], LeaderAction)] = &[
+    // Leader, G, S types greeting
+    (&[b::KEY_G, b::KEY_S], LeaderAction::Key(macro_key(0))),
+    // Leader, F, N toggles Fn layer
+    (&[b::KEY_F, b::KEY_N], LeaderAction::ToggleFnLayer),
+    // Leader, M mutes
+    (&[b::KEY_M], LeaderAction::SystemKey(b::KEY_MEDIA_MUTE)),
+    // Leader, C, W toggles Caps Word
+    (&[b::KEY_C, b::KEY_W], LeaderAction::Key(CAPS_WORD_KEY)),
+    // Leader, O, L / O, W / O, M selects Linux, Windows or Mac host profile
+    (&[b::KEY_O, b::KEY_L], LeaderAction::Key(profile_key(OsProfile::Linux))),
+    (&[b::KEY_O, b::KEY_W], LeaderAction::Key(profile_key(OsProfile::Windows))),
+    (&[b::KEY_O, b::KEY_M], LeaderAction::Key(profile_key(OsProfile::Mac))),
+    // Leader, K, Q / K, D / K, C selects Qwerty, Dvorak or Colemak letter layout
+    (&[b::KEY_K, b::KEY_Q], LeaderAction::Key(layout_key(Layout::Qwerty))),
+    (&[b::KEY_K, b::KEY_D], LeaderAction::Key(layout_key(Layout::Dvorak))),
+    (&[b::KEY_K, b::KEY_C], LeaderAction::Key(layout_key(Layout::Colemak))),
+];
+
+/// Key overrides, i.e. modifier + key combinations that send a different key, see
+/// `key_overrides`.
+const KEY_OVERRIDES: &[KeyOverride] = &[
+    // Shift + Backspace sends Delete
+    // KeyOverride {
+    //     modifiers: b::MODIFIERKEY_LEFT_SHIFT | b::MODIFIERKEY_RIGHT_SHIFT,
+    //     key: b::KEY_BACKSPACE,
+    //     replacement: b::KEY_DELETE,
+    // },
+    // Ctrl + Esc sends Caps Lock
+    // KeyOverride {
+    //     modifiers: b::MODIFIERKEY_LEFT_CTRL | b::MODIFIERKEY_RIGHT_CTRL,
+    //     key: b::KEY_ESC,
+    //     replacement: b::KEY_CAPS_LOCK,
+    // },
+];
+
+/// Remappings of host OS profiles, see `os_profile`. In order Linux, Windows and Mac.
+const OS_PROFILES: [ProfileMap; 3] = [
+    // Linux
+    ProfileMap { modifiers: &[], keys: &[], media: &[] },
+    // Windows
+    ProfileMap { modifiers: &[], keys: &[], media: &[] },
+    // Mac
+    ProfileMap {
+        // Cmd is next to space, where Alt is on PC keyboards
+        modifiers: &[
+            (b::MODIFIERKEY_LEFT_ALT, b::MODIFIERKEY_LEFT_GUI),
+            (b::MODIFIERKEY_LEFT_GUI, b::MODIFIERKEY_LEFT_ALT),
+        ],
+        keys: &[
+            // Print Screen takes screenshot of selection (Cmd + Shift + 4)
+            (b::KEY_PRINTSCREEN, b::KEY_4, b::MODIFIERKEY_LEFT_GUI | b::MODIFIERKEY_LEFT_SHIFT),
+            // Insert is F13, which can be bound to anything
+            (b::KEY_INSERT, b::KEY_F13, 0),
+        ],
+        media: &[
+            // (b::KEY_MEDIA_MUTE, b::KEY_MEDIA_EJECT),
+        ],
+    },
+];
+
+/// Letter layouts done in firmware, see `layouts`. Tuple is (QWERTY key, key of the layout at
+/// that position). In order Qwerty, Dvorak and Colemak.
+///
+/// Host layout is Finnish, so only letters A-Z are moved, and keys of Å, Ö, Ä and punctuation
+/// stay where they are. Letters that the layout has on punctuation keys are put on the letter
+/// keys that the layout uses for punctuation instead, e.g. in Dvorak S is on Q and V is on E.
+const LAYOUTS: [&[(u32, u32)]; 3] = [
+    // Qwerty
+    &[],
+    // Dvorak
+    &[
+        (b::KEY_Q, b::KEY_S), (b::KEY_E, b::KEY_V),
+        (b::KEY_R, b::KEY_P), (b::KEY_T, b::KEY_Y), (b::KEY_Y, b::KEY_F), (b::KEY_U, b::KEY_G),
+        (b::KEY_I, b::KEY_C), (b::KEY_O, b::KEY_R), (b::KEY_P, b::KEY_L),
+        (b::KEY_S, b::KEY_O), (b::KEY_D, b::KEY_E), (b::KEY_F, b::KEY_U), (b::KEY_G, b::KEY_I),
+        (b::KEY_H, b::KEY_D), (b::KEY_J, b::KEY_H), (b::KEY_K, b::KEY_T), (b::KEY_L, b::KEY_N),
+        (b::KEY_X, b::KEY_Q), (b::KEY_C, b::KEY_J), (b::KEY_V, b::KEY_K), (b::KEY_B, b::KEY_X),
+        (b::KEY_N, b::KEY_B),
+    ],
+    // Colemak
+    &[
+        (b::KEY_E, b::KEY_F), (b::KEY_R, b::KEY_P), (b::KEY_T, b::KEY_G), (b::KEY_Y, b::KEY_J),
+        (b::KEY_U, b::KEY_L), (b::KEY_I, b::KEY_U), (b::KEY_O, b::KEY_Y), (b::KEY_P, b::KEY_O),
+        (b::KEY_S, b::KEY_R), (b::KEY_D, b::KEY_S), (b::KEY_F, b::KEY_T), (b::KEY_G, b::KEY_D),
+        (b::KEY_J, b::KEY_N), (b::KEY_K, b::KEY_E), (b::KEY_L, b::KEY_I), (b::KEY_N, b::KEY_K),
+    ],
+];
+
+/// Repeat settings of individual keys in firmware typematic mode, see `typematic`. Only
+/// `Repeat::Off` has an effect on media keys.
+const KEY_REPEATS: &[(u32, Repeat)] = &[
+    (b::KEY_MEDIA_MUTE, Repeat::Off),
+    (b::KEY_MEDIA_VOLUME_DEC, Repeat::Off),
+    (b::KEY_MEDIA_VOLUME_INC, Repeat::Off),
+    (b::KEY_ESC, Repeat::Off),
+    (b::KEY_BACKSPACE, Repeat::On { delay: 300_000, interval: 20_000 }),
+];
+
 /// This represents spatial configuration of my keyboard, row by row.
 const KEY_CODES: &[&[u32]] = &[
     // Special keys
@@ -55,14 +202,13 @@ const KEY_NAMES: &[&[&str]] = &[
 /// output to `get_stored_key_codes`.
 #[allow(dead_code)]
 pub fn ask_key_codes_and_print_them(pinrow: &mut PinRow) -> KeyMatrix {
-    let info = extra_information_about_key_codes();
-    let mat = figure_out_key_matrix(pinrow, KEY_CODES, KEY_NAMES, info);
+    let mut mat = figure_out_key_matrix(pinrow, KEY_CODES, KEY_NAMES, DIODES);
+    mat.add_direct_keys(pinrow, DIRECT_KEYS);
     return mat;
 }
 
 /// This function contains key codes that are generated with `ask_key_codes_and_print_them`
 pub fn get_stored_key_codes(pinrow: &mut PinRow) -> KeyMatrix {
-    let info = extra_information_about_key_codes();
     // autogenerated with `ask_key_codes_and_print_them`
     let code_matrix = [
         [                       0,                  0,        0,                       0,              0,                0, b::MODIFIERKEY_RIGHT_SHIFT, b::MODIFIERKEY_LEFT_SHIFT,                        0, ],
@@ -87,12 +233,79 @@ pub fn get_stored_key_codes(pinrow: &mut PinRow) -> KeyMatrix {
         .collect();
     let rows = Vec::from_slice(&[1, 5, 6, 7, 8, 9, 10, 11, 12, 14, 15, 16, 17, 19, 24, 25, 37]).unwrap();
     let cols = Vec::from_slice(&[0, 2, 3, 4, 18, 20, 21, 22, 28]).unwrap();
-    let mat = KeyMatrix::new(pinrow, code_matrix, rows, cols, info);
+    let mut mat = KeyMatrix::new(pinrow, code_matrix, rows, cols, DIODES);
+    mat.add_direct_keys(pinrow, DIRECT_KEYS);
 
     return mat;
 }
 
 
+/// Locate keys of `COMBOS` in key matrices
+pub fn get_combos(mats: &KeyMatrices, window: u32) -> Combos {
+    let mut combos = Combos::new(window);
+    for &(codes, code) in COMBOS.iter() {
+        let keys: Option<ShortVec<KeyPos>> = codes.iter().map(|&c| mats.position_of(c)).collect();
+        match keys {
+            Some(keys) => combos.add(&keys, code).unwrap_or_else(|_| {
+                println!("Warning! Too many combos.");
+            }),
+            None => println!("Warning! Some key of combo {:?} is not in key matrix.", codes),
+        }
+    }
+    return combos;
+}
+
+/// Locate keys of `TAP_DANCES` in key matrices
+pub fn get_tap_dances(mats: &KeyMatrices, term: u32) -> TapDances {
+    let mut tap_dances = TapDances::new(term);
+    for &(code, taps, hold) in TAP_DANCES.iter() {
+        match mats.position_of(code) {
+            Some(pos) => tap_dances.add(pos, taps, hold).unwrap_or_else(|_| {
+                println!("Warning! Too many tap dance keys.");
+            }),
+            None => println!("Warning! Tap dance key {} is not in key matrix.", code),
+        }
+    }
+    return tap_dances;
+}
+
+/// Firmware typematic mode with repeat settings of `KEY_REPEATS`
+pub fn get_typematic(enabled: bool, default: Repeat) -> Typematic {
+    return Typematic::new(enabled, default, KEY_REPEATS);
+}
+
+/// Letter layouts of `LAYOUTS`. Shortcuts with `shortcut_modifiers` stay on QWERTY positions.
+pub fn get_layouts(shortcut_modifiers: u32) -> Layouts {
+    return Layouts::new(&LAYOUTS, shortcut_modifiers);
+}
+
+/// Host OS profiles with remappings of `OS_PROFILES`
+pub fn get_os_profiles() -> OsProfiles {
+    return OsProfiles::new(&OS_PROFILES);
+}
+
+/// Key overrides of `KEY_OVERRIDES`
+pub fn get_key_overrides() -> KeyOverrides {
+    return KeyOverrides::new(KEY_OVERRIDES);
+}
+
+/// Build trie of `LEADER_SEQUENCES`
+pub fn get_leader(timeout: u32) -> Leader {
+    let mut leader = Leader::new(timeout);
+    for &(sequence, action) in LEADER_SEQUENCES.iter() {
+        leader.add(sequence, action).unwrap_or_else(|_| {
+            println!("Warning! Too many leader sequences.");
+        });
+    }
+    return leader;
+}
+
+/// Print key press statistics in the shape of my keyboard layout. The output can be rendered as a
+/// heat map with `render_heat_map.py`.
+pub fn print_key_statistics(stats: &KeyStats, mats: &KeyMatrices) {
+    stats.print(mats, KEY_CODES, KEY_NAMES);
+}
+
 /// This function is my custom configuration, for some small details about key codes.
 /// This contains information about Fn key, media keys, and the byte masks of key codes.
 /// The only thing that should need configuration is `media_key_bindings`. All others
@@ -126,8 +339,18 @@ pub fn extra_information_about_key_codes() -> ExtraKeyInfo {
     // Fn key mask must be different to regular keys and modifiers
     let fn_key_mask = fn_key.to_le_bytes()[1];
     assert!((fn_key_mask != regular_key_mask) && (fn_key_mask != modifier_key_mask));
+    // Macro keys are 0xE900, 0xE901, ... so they do not collide with any of the above
+    let macro_key_mask = macro_key(0).to_le_bytes()[1];
+    assert!(![regular_key_mask, modifier_key_mask, fn_key_mask].contains(&macro_key_mask));
 
-    return ExtraKeyInfo{fn_key, media_key_bindings, regular_key_mask, modifier_key_mask};
+    return ExtraKeyInfo{
+        fn_key,
+        media_key_bindings,
+        regular_key_mask,
+        modifier_key_mask,
+        macro_key_mask,
+        macros: MACROS,
+    };
 }
 
 /*
@@ -153,5 +376,22 @@ PRINTSCREEN     0  LEFT_ALT       0         0           0           0         0
 
 */
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-
+    #[test]
+    fn layouts_only_permute_letters() {
+        let is_letter = |code: u32| (b::KEY_A..=b::KEY_Z).contains(&code);
+        for layout in LAYOUTS.iter() {
+            let mut from: std::vec::Vec<u32> = layout.iter().map(|&(f, _)| f).collect();
+            let mut to: std::vec::Vec<u32> = layout.iter().map(|&(_, t)| t).collect();
+            assert!(from.iter().chain(to.iter()).all(|&c| is_letter(c)));
+            from.sort_unstable();
+            to.sort_unstable();
+            from.dedup();
+            assert_eq!(from.len(), layout.len());
+            assert_eq!(from, to);
+        }
+    }
+}
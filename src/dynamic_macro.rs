@@ -0,0 +1,163 @@
+//! Dynamic macro, which is recorded on the fly. Press `RECORD_KEY`, type something, and press
+//! `RECORD_KEY` again. After that `PLAY_KEY` types the same thing again. The recorded steps are
+//! the changes of USB reports, so they contain the keys after Fn layer and debouncing. Media keys
+//! are not recorded.
+//!
+//! Recording is kept in a ring buffer, so if it gets too long, the oldest steps are overwritten.
+//! Recording can be saved to EEPROM, so that it survives power off.
+
+use heapless::spsc::Queue;
+use heapless::Vec; // fixed capacity `std::Vec`
+use typenum::Unsigned;
+
+use crate::eeprom;
+use crate::macros::{macro_key, MacroStep, RecordCap};
+
+/// Key that starts and stops recording
+pub const RECORD_KEY: u32 = macro_key(0xFE);
+/// Key that plays the recorded macro
+pub const PLAY_KEY: u32 = macro_key(0xFF);
+
+/// Recognizes saved macro in EEPROM
+const MAGIC: u8 = 0xD1;
+/// Size of one step in EEPROM: key code (2 bytes) and press/release flag
+const STEP_LEN: usize = 3;
+
+/// Recorder of dynamic macro
+#[derive(Debug)]
+pub struct DynamicMacro {
+    /// Whether recording is saved to EEPROM
+    pub persist: bool,
+    recording: bool,
+    /// Key code and whether it is pressed or released
+    steps: Queue<(u16, bool), RecordCap>,
+    /// Number of steps that have been overwritten in current recording
+    overwritten: u16,
+}
+
+impl DynamicMacro {
+    /// Create empty recorder. If `persist` is true, the previous recording is loaded from EEPROM.
+    pub fn new(persist: bool) -> DynamicMacro {
+        let mut dm = DynamicMacro {
+            persist,
+            recording: false,
+            steps: Queue::new(),
+            overwritten: 0,
+        };
+        if persist {
+            dm.load();
+        }
+        return dm;
+    }
+
+    pub fn is_recording(&self) -> bool {
+        return self.recording;
+    }
+
+    /// Start recording, or stop it if it is already on
+    pub fn toggle_recording(&mut self) {
+        self.recording = !self.recording;
+        if self.recording {
+            self.steps = Queue::new();
+            self.overwritten = 0;
+            println!("Recording dynamic macro...");
+        } else {
+            println!("Recorded dynamic macro of {} steps", self.steps.len());
+            if self.overwritten > 0 {
+                println!("    ({} first steps were overwritten)", self.overwritten);
+            }
+            if self.persist {
+                self.save();
+            }
+        }
+    }
+
+    /// Record changes between previous report and current report. Releases are recorded before
+    /// presses.
+    /// # Arguments
+    /// * `modifiers_old` Modifier keys of the previous report, as `modifier_slots` in `main`
+    /// * `modifiers`     Modifier keys of the current report
+    /// * `keys_old`      Regular keys of the previous report
+    /// * `keys`          Regular keys of the current report
+    pub fn record_report(
+        &mut self,
+        modifiers_old: u16,
+        modifiers: u16,
+        keys_old: &[Option<u8>; 6],
+        keys: &[Option<u8>; 6],
+    ) {
+        for bit in (0..8).map(|i| 1u16 << i) {
+            if modifiers_old & bit != 0 && modifiers & bit == 0 {
+                self.record(0xE000 | bit, false);
+            }
+        }
+        for k in keys_old.iter().filter_map(|&k| k).filter(|&k| !keys.contains(&Some(k))) {
+            self.record(0xF000 | k as u16, false);
+        }
+        for bit in (0..8).map(|i| 1u16 << i) {
+            if modifiers_old & bit == 0 && modifiers & bit != 0 {
+                self.record(0xE000 | bit, true);
+            }
+        }
+        for k in keys.iter().filter_map(|&k| k).filter(|&k| !keys_old.contains(&Some(k))) {
+            self.record(0xF000 | k as u16, true);
+        }
+    }
+
+    fn record(&mut self, code: u16, pressed: bool) {
+        if let Err(step) = self.steps.enqueue((code, pressed)) {
+            if self.overwritten == 0 {
+                println!("Dynamic macro is full, overwriting the oldest steps");
+            }
+            self.overwritten = self.overwritten.saturating_add(1);
+            self.steps.dequeue();
+            self.steps.enqueue(step).unwrap_or(());
+        }
+    }
+
+    /// Recorded macro as steps for `MacroPlayer`
+    pub fn steps(&self) -> Vec<MacroStep, RecordCap> {
+        return self.steps.iter()
+            .map(|&(code, pressed)| match pressed {
+                true => MacroStep::Press(code as u32),
+                false => MacroStep::Release(code as u32),
+            })
+            .collect();
+    }
+
+    /// Print record state and recorded steps
+    pub fn print(&self) {
+        println!("Dynamic macro: {} steps, recording {}",
+                 self.steps.len(), if self.recording { "on" } else { "off" });
+        for step in self.steps().iter() {
+            println!("    {:?}", step);
+        }
+    }
+
+    fn save(&self) {
+        let mut buf = [0u8; 2 + STEP_LEN * RecordCap::USIZE];
+        buf[0] = MAGIC;
+        buf[1] = self.steps.len() as u8;
+        for (chunk, &(code, pressed)) in buf[2..].chunks_mut(STEP_LEN).zip(self.steps.iter()) {
+            chunk[..2].copy_from_slice(&code.to_le_bytes());
+            chunk[2] = pressed as u8;
+        }
+        let len = 2 + STEP_LEN * self.steps.len();
+        eeprom::write(eeprom::DYNAMIC_MACRO_ADDR, &buf[..len]);
+    }
+
+    fn load(&mut self) {
+        let mut header = [0u8; 2];
+        eeprom::read(eeprom::DYNAMIC_MACRO_ADDR, &mut header);
+        if header[0] != MAGIC || header[1] as usize > RecordCap::USIZE {
+            return;
+        }
+        let mut buf = [0u8; STEP_LEN * RecordCap::USIZE];
+        let len = STEP_LEN * header[1] as usize;
+        eeprom::read(eeprom::DYNAMIC_MACRO_ADDR + 2, &mut buf[..len]);
+        for chunk in buf[..len].chunks(STEP_LEN) {
+            let code = u16::from_le_bytes([chunk[0], chunk[1]]);
+            self.steps.enqueue((code, chunk[2] != 0)).unwrap_or(());
+        }
+    }
+}
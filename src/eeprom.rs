@@ -0,0 +1,60 @@
+//! Persistent storage in EEPROM. Teensy 3.0-3.2 have 2 KiB of EEPROM and 3.5-3.6 have 4 KiB, so
+//! everything must fit in the first 2048 bytes. The addresses of stored items are listed here so
+//! that they do not overlap.
+//!
+//! Every stored item starts with a magic byte, so that garbage (e.g. fresh EEPROM, which is full
+//! of 0xFF) is not mistaken for valid data.
+
+#[cfg(not(test))]
+use teensy3::bindings as b;
+
+/// Selected host OS profile, see `os_profile`
+pub const OS_PROFILE_ADDR: usize = 0;
+/// Selected letter layout, see `layouts`
+pub const LAYOUT_ADDR: usize = 2;
+/// Calibrated settle times of key matrices, see `KeyMatrices::calibrate_settle_times`
+pub const SETTLE_TIMES_ADDR: usize = 4;
+/// Size of settle times: magic byte and two bytes for each of at most four matrices
+pub const SETTLE_TIMES_LEN: usize = 1 + 2 * 4;
+/// Tuned debounce times of chattering keys, see `Debouncer::save_debounce_times`
+pub const DEBOUNCE_TIMES_ADDR: usize = 16;
+/// Maximum size of debounce times: magic byte, count and 7 bytes for each of at most 24 keys
+pub const DEBOUNCE_TIMES_LEN: usize = 2 + 7 * 24;
+/// Recorded dynamic macro, see `dynamic_macro`
+pub const DYNAMIC_MACRO_ADDR: usize = 256;
+/// Key press statistics, see `stats`
+pub const STATS_ADDR: usize = 512;
+/// Maximum size of key press statistics
+pub const STATS_LEN: usize = 2048 - STATS_ADDR;
+
+/// Read bytes starting from `addr`
+#[cfg(not(test))]
+pub fn read(addr: usize, buf: &mut [u8]) {
+    for (i, byte) in buf.iter_mut().enumerate() {
+        *byte = unsafe { b::eeprom_read_byte((addr + i) as *const u8) };
+    }
+}
+
+/// Write bytes starting from `addr`. Bytes that are already same are not written again, so that
+/// EEPROM does not wear out needlessly.
+#[cfg(not(test))]
+pub fn write(addr: usize, data: &[u8]) {
+    for (i, &byte) in data.iter().enumerate() {
+        let ptr = (addr + i) as *mut u8;
+        unsafe {
+            if b::eeprom_read_byte(ptr) != byte {
+                b::eeprom_write_byte(ptr, byte);
+            }
+        }
+    }
+}
+
+/// Unit tests see fresh EEPROM
+#[cfg(test)]
+pub fn read(_addr: usize, buf: &mut [u8]) {
+    buf.iter_mut().for_each(|byte| *byte = 0xFF);
+}
+
+/// Unit tests do not save anything
+#[cfg(test)]
+pub fn write(_addr: usize, _data: &[u8]) {}
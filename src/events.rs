@@ -0,0 +1,146 @@
+//! Key events connect the scanning stage to the reporting stage. Scanning produces timestamped
+//! press and release events into a queue, and reporting drains the queue at the USB polling
+//! interval. This way scan rate, debounce time and report rate can be configured independently,
+//! and the time stamps tell how long an event has waited before it is sent.
+
+use heapless::spsc::Queue;
+use heapless::{ArrayLength, Vec}; // fixed capacity `std::Vec`
+use typenum::U64 as EventsCap;
+
+use crate::process_keys::{KeyCode, KeyPos};
+use crate::ShortVec;
+
+/// Change of a key state
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct KeyEvent {
+    /// Position of the key
+    pub pos: KeyPos,
+    /// Key code. The press event is sent again if the certainty of the press changes.
+    pub code: KeyCode<u32>,
+    /// True for press, false for release
+    pub pressed: bool,
+    /// Time of the scan where the change was seen, in microseconds. (Wraps around in 71 minutes.)
+    pub time: u32,
+    /// Time when the change passed the debouncing, in microseconds
+    pub debounced: u32,
+}
+
+// Key matrix indices of virtual keys. Stages that emit keys of their own give them positions in
+// these matrices, so that they do not collide with real keys. The indices are listed here so that
+// they do not overlap.
+
+/// Virtual Shift key of auto-shift, see `auto_shift`
+pub const AUTO_SHIFT_MATRIX: u8 = 0xFB;
+/// Virtual Shift key of Caps Word, see `caps_word`
+pub const CAPS_WORD_MATRIX: u8 = 0xFC;
+/// Tapped keys of leader sequences, see `leader`
+pub const LEADER_MATRIX: u8 = 0xFD;
+/// Actions of tap dance keys, see `TapDances::position_of`
+pub const TAP_DANCE_MATRIX: u8 = 0xFE;
+/// Combo keys, see `Combos::position_of`
+pub const COMBO_MATRIX: u8 = 0xFF;
+
+impl KeyEvent {
+    /// Event of key that is not scanned but generated by some stage, e.g. virtual Shift key.
+    /// It is certain, and debounced at the time it happens.
+    pub fn generated(pos: KeyPos, code: u32, pressed: bool, time: u32) -> KeyEvent {
+        return KeyEvent { pos, code: KeyCode::Certain(code), pressed, time, debounced: time };
+    }
+}
+
+/// Queue of key events from the scanning stage to the reporting stage
+pub type EventQueue = Queue<KeyEvent, EventsCap>;
+/// Key events that are processed in one report
+pub type EventVec = Vec<KeyEvent, EventsCap>;
+
+/// Push event to queue. If the queue is full, event is dropped with a warning. That should not
+/// happen, as the queue is drained every millisecond or so.
+pub fn push_event(events: &mut EventQueue, event: KeyEvent) {
+    if events.enqueue(event).is_err() {
+        println!("Warning! Event queue is full, dropping event {:?}.", event);
+    }
+}
+
+/// Push event to list of events of one report. If the list is full, event is dropped with a
+/// warning.
+pub fn push_warn<U: ArrayLength<KeyEvent>>(events: &mut Vec<KeyEvent, U>, event: KeyEvent) {
+    if events.push(event).is_err() {
+        println!("Warning! Too many key events, dropping event {:?}.", event);
+    }
+}
+
+/// Update the list of currently pressed keys according to event. The list is in the same form
+/// as the scan result of `KeyMatrices::scan_key_press`.
+pub fn apply_event(held: &mut ShortVec<(KeyPos, KeyCode<u32>)>, event: &KeyEvent) {
+    let idx = held.iter().position(|&(pos, _)| pos == event.pos);
+    match (idx, event.pressed) {
+        (Some(i), true) => held[i].1 = event.code,
+        (Some(i), false) => { held.swap_remove(i); }
+        (None, true) => held.push((event.pos, event.code)).unwrap_or(()),
+        (None, false) => {}
+    }
+}
+
+/// Keep track of when the currently pressed keys were pressed. Press time is the time of the
+/// first press event, so changes of certainty do not update it.
+pub fn update_press_times(press_times: &mut ShortVec<(KeyPos, u32)>, event: &KeyEvent) {
+    let idx = press_times.iter().position(|&(pos, _)| pos == event.pos);
+    match (idx, event.pressed) {
+        (Some(i), false) => { press_times.swap_remove(i); }
+        (None, true) => press_times.push((event.pos, event.time)).unwrap_or(()),
+        _ => {}
+    }
+}
+
+/// Fixtures for unit tests of the stages that process key events
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+
+    /// Certain event of key at `pos`. Key code is derived from the position, so that position
+    /// (0, 0, 0) is A and the following ones are the next letters.
+    pub fn event(pos: KeyPos, pressed: bool, time: u32) -> KeyEvent {
+        let code = 0xF004 + pos.col as u32 + 8 * pos.row as u32;
+        return KeyEvent::generated(pos, code, pressed, time);
+    }
+
+    /// Certain event of key with `code`. Position is derived from the code.
+    pub fn key_event(code: u32, pressed: bool, time: u32) -> KeyEvent {
+        let pos = KeyPos::new(0, 0, code as usize & 0xFF);
+        return KeyEvent::generated(pos, code, pressed, time);
+    }
+
+    /// Feed timed events to an event processing stage, and return the events it outputs. Like
+    /// the report stage, this calls `tick(stage, now, out)` every millisecond from the time of
+    /// the first event until `end` and all events are processed. Each event is passed to
+    /// `process(stage, event, out)` on the first tick at or after its time.
+    pub fn run<S, T, P>(
+        stage: &mut S,
+        events: &[KeyEvent],
+        end: u32,
+        mut tick: T,
+        mut process: P,
+    ) -> std::vec::Vec<KeyEvent>
+    where
+        T: FnMut(&mut S, u32, &mut EventVec),
+        P: FnMut(&mut S, KeyEvent, &mut EventVec),
+    {
+        let mut out = EventVec::new();
+        let mut result = std::vec::Vec::new();
+        let mut now = events.first().map_or(0, |e| e.time);
+        let mut events = events.iter().peekable();
+        loop {
+            tick(stage, now, &mut out);
+            while let Some(&&e) = events.peek().filter(|e| e.time <= now) {
+                process(stage, e, &mut out);
+                events.next();
+            }
+            result.extend(out.iter().copied());
+            out.clear();
+            if now >= end && events.peek().is_none() {
+                return result;
+            }
+            now += 1000;
+        }
+    }
+}
@@ -0,0 +1,134 @@
+//! Low-power idle mode. Scanning the key matrix every 10 ms keeps the controller awake all the
+//! time, even if nothing is pressed for hours. So after a while of inactivity, all drains are
+//! enabled, pin interrupts are attached on the source pins, and the controller is put to sleep.
+//! The first key press wakes it up, and full-rate scanning resumes.
+//!
+//! Transitions between modes are decided by `IdleState`, which does not touch any hardware.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::process_keys::KeyMatrices;
+
+/// Power mode of the keyboard controller
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PowerMode {
+    /// Keys are scanned at full rate
+    Scanning,
+    /// Controller sleeps until some key is pressed
+    Sleeping,
+}
+
+/// State machine that decides when to go to sleep. It counts time since the last activity, and
+/// when `idle_timeout` is reached, it switches to `Sleeping`. Call `wake_up` to switch back.
+#[derive(Debug)]
+pub struct IdleState {
+    /// Current power mode
+    pub mode: PowerMode,
+    /// Inactivity time (in milliseconds) after which the controller goes to sleep
+    pub idle_timeout: u32,
+    /// Time (in milliseconds) since something was pressed last time
+    pub inactive_time: u32,
+}
+
+impl IdleState {
+    pub fn new(idle_timeout: u32) -> IdleState {
+        IdleState { mode: PowerMode::Scanning, idle_timeout, inactive_time: 0 }
+    }
+
+    /// Advance the state by one scan interval, and return the new power mode.
+    /// # Arguments
+    /// * `active`  Whether some key is pressed or still waits to be released
+    /// * `elapsed` Time (in milliseconds) since previous call
+    pub fn update(&mut self, active: bool, elapsed: u32) -> PowerMode {
+        if self.mode == PowerMode::Sleeping {
+            return self.mode;  // Only `wake_up` can change this
+        }
+        if active {
+            self.inactive_time = 0;
+        } else {
+            self.inactive_time = self.inactive_time.saturating_add(elapsed);
+        }
+        if self.inactive_time >= self.idle_timeout {
+            self.mode = PowerMode::Sleeping;
+        }
+        return self.mode;
+    }
+
+    /// Return to full-rate scanning. Idle time starts counting again from zero.
+    pub fn wake_up(&mut self) {
+        self.mode = PowerMode::Scanning;
+        self.inactive_time = 0;
+    }
+}
+
+/// Set by pin interrupt when some key is pressed during sleep
+static WOKEN: AtomicBool = AtomicBool::new(false);
+
+/// Interrupt service routine attached to source pins during sleep.
+unsafe extern "C" fn wake_up_isr() {
+    WOKEN.store(true, Ordering::SeqCst);
+}
+
+/// Sleep until some key is pressed. Matrices are prepared for pin interrupts, and the controller
+/// waits for interrupt in low-power mode. (Timer interrupts also wake it up every millisecond,
+/// but then it just goes back to sleep.) Pins are restored for scanning before returning.
+pub fn sleep_until_key_press(mats: &mut KeyMatrices) {
+    WOKEN.store(false, Ordering::SeqCst);
+    mats.set_wake_on_key_press(true, wake_up_isr);
+    while !WOKEN.load(Ordering::SeqCst) {
+        wait_for_interrupt();
+    }
+    mats.set_wake_on_key_press(false, wake_up_isr);
+}
+
+/// Halt the processor until the next interrupt. The timer interrupts every millisecond, so this
+/// never waits longer than that. (Inline assembly requires Rust 1.59.)
+pub fn wait_for_interrupt() {
+    #[cfg(target_arch = "arm")]
+    unsafe {
+        core::arch::asm!("wfi");
+    }
+    #[cfg(not(target_arch = "arm"))]
+    core::hint::spin_loop();  // Host build for unit tests
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sleeps_after_timeout() {
+        let mut idle = IdleState::new(100);
+        assert_eq!(idle.update(false, 60), PowerMode::Scanning);
+        assert_eq!(idle.update(false, 39), PowerMode::Scanning);
+        assert_eq!(idle.update(false, 1), PowerMode::Sleeping);
+    }
+
+    #[test]
+    fn activity_restarts_timeout() {
+        let mut idle = IdleState::new(100);
+        assert_eq!(idle.update(false, 90), PowerMode::Scanning);
+        assert_eq!(idle.update(true, 10), PowerMode::Scanning);
+        assert_eq!(idle.inactive_time, 0);
+        assert_eq!(idle.update(false, 90), PowerMode::Scanning);
+        assert_eq!(idle.update(false, 10), PowerMode::Sleeping);
+    }
+
+    #[test]
+    fn stays_asleep_until_woken() {
+        let mut idle = IdleState::new(100);
+        assert_eq!(idle.update(false, 100), PowerMode::Sleeping);
+        assert_eq!(idle.update(true, 10), PowerMode::Sleeping);
+        idle.wake_up();
+        assert_eq!(idle.mode, PowerMode::Scanning);
+        assert_eq!(idle.inactive_time, 0);
+        assert_eq!(idle.update(false, 99), PowerMode::Scanning);
+    }
+
+    #[test]
+    fn inactive_time_saturates() {
+        let mut idle = IdleState::new(u32::MAX);
+        idle.update(false, u32::MAX - 1);
+        assert_eq!(idle.update(false, 10), PowerMode::Sleeping);
+    }
+}
@@ -0,0 +1,139 @@
+//! Key overrides, i.e. modifier + key combinations that send a different key. For example,
+//! Shift + Backspace could send Delete. The modifier is consumed, so the host sees only Delete.
+//!
+//! Override is activated when its key is pressed while its modifier is held, and it stays active
+//! until the key is released, regardless of the order in which the modifier and the key are
+//! released. Pressing modifier while the key is already held does not activate override.
+//! Overrides are not activated while Fn is pressed.
+
+use crate::process_keys::{ExtraKeyInfo, KeyCode};
+use crate::ShortVec;
+use heapless::Vec; // fixed capacity `std::Vec`
+
+/// Rule that replaces modifier + key by another key
+#[derive(Debug, Copy, Clone)]
+pub struct KeyOverride {
+    /// Modifier key codes, e.g. `b::MODIFIERKEY_LEFT_SHIFT | b::MODIFIERKEY_RIGHT_SHIFT`. Any of
+    /// them activates override, and all of them are consumed.
+    pub modifiers: u32,
+    /// Key code of the key that is replaced. Only regular keys can be replaced.
+    pub key: u32,
+    /// Key code that is sent instead. It must be a regular key too.
+    pub replacement: u32,
+}
+
+/// Applies key overrides
+#[derive(Debug)]
+pub struct KeyOverrides {
+    rules: &'static [KeyOverride],
+    /// Indices of rules that are active
+    active: ShortVec<usize>,
+    /// Regular keys that were pressed on previous report, before overrides
+    keys_prev: ShortVec<u8>,
+}
+
+impl KeyOverrides {
+    pub fn new(rules: &'static [KeyOverride]) -> KeyOverrides {
+        return KeyOverrides { rules, active: Vec::new(), keys_prev: Vec::new() };
+    }
+
+    /// Replace keys of active overrides in `regular_keys`, and return modifiers without the
+    /// consumed ones.
+    /// # Arguments
+    /// * `modifier_slots` Pressed modifiers, as `modifier_slots` in `main`
+    /// * `regular_keys`   Pressed regular keys, from `categorize_key_presses`
+    /// * `fn_key`         Whether Fn is pressed
+    pub fn apply(
+        &mut self,
+        modifier_slots: u16,
+        regular_keys: &mut ShortVec<KeyCode<u8>>,
+        fn_key: bool,
+        info: &ExtraKeyInfo,
+    ) -> u16 {
+        let keys: ShortVec<u8> = regular_keys.iter().map(|k| k.into_inner()).collect();
+        let rules = self.rules;
+        // Deactivate overrides whose key is released
+        let mut a = 0;
+        while a < self.active.len() {
+            if keys.contains(&(rules[self.active[a]].key as u8)) {
+                a += 1;
+            } else {
+                self.active.swap_remove(a);
+            }
+        }
+        // Activate overrides whose key is just pressed
+        for (i, rule) in rules.iter().enumerate() {
+            // Low byte alone would match also e.g. modifiers and media keys
+            let is_regular = |code: u32| code.to_le_bytes()[1] == info.regular_key_mask;
+            if !is_regular(rule.key) || !is_regular(rule.replacement) {
+                continue;
+            }
+            let key = rule.key as u8;
+            let newly_pressed = regular_keys.iter()
+                .any(|k| k.into_option() == Some(key) && !self.keys_prev.contains(&key));
+            let modifier_held = modifier_slots & rule.modifiers as u16 & 0xFF != 0;
+            if !fn_key && newly_pressed && modifier_held && !self.active.contains(&i) {
+                self.active.push(i).unwrap_or(());
+            }
+        }
+        self.keys_prev = keys;
+
+        // Active overrides have regular keys, so their low bytes are regular key codes
+        let mut modifiers = modifier_slots;
+        for &i in self.active.iter() {
+            let rule = rules[i];
+            for k in regular_keys.iter_mut().filter(|k| k.into_inner() == rule.key as u8) {
+                *k = match *k {
+                    KeyCode::Certain(_) => KeyCode::Certain(rule.replacement as u8),
+                    KeyCode::Uncertain(_) => KeyCode::Uncertain(rule.replacement as u8),
+                };
+            }
+            modifiers &= !(rule.modifiers as u16 & 0xFF);
+        }
+        // Modifier slots are zero when no modifier is pressed
+        return if modifiers & 0xFF == 0 { 0 } else { modifiers };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHIFT: u16 = 0xE002;
+    const KEY_BACKSPACE: u8 = 0x2A;
+    const KEY_DELETE: u8 = 0x4C;
+
+    fn keys(codes: &[u8]) -> ShortVec<KeyCode<u8>> {
+        return codes.iter().map(|&c| KeyCode::Certain(c)).collect();
+    }
+
+    #[test]
+    fn shift_backspace_sends_delete() {
+        static RULES: [KeyOverride; 1] = [KeyOverride {
+            modifiers: 0xE002 | 0xE020,
+            key: 0xF000 | KEY_BACKSPACE as u32,
+            replacement: 0xF000 | KEY_DELETE as u32,
+        }];
+        let info = crate::custom_key_codes::extra_information_about_key_codes();
+        let mut overrides = KeyOverrides::new(&RULES);
+        let mut pressed = keys(&[KEY_BACKSPACE]);
+        assert_eq!(overrides.apply(SHIFT, &mut pressed, false, &info), 0);
+        assert_eq!(&pressed[..], &keys(&[KEY_DELETE])[..]);
+        // Override stays active after Shift is released
+        let mut pressed = keys(&[KEY_BACKSPACE]);
+        assert_eq!(overrides.apply(0, &mut pressed, false, &info), 0);
+        assert_eq!(&pressed[..], &keys(&[KEY_DELETE])[..]);
+    }
+
+    #[test]
+    fn only_regular_key_codes_are_replaced() {
+        // Low byte of media key Mute (0xE404) is the same as regular key A
+        static RULES: [KeyOverride; 1] =
+            [KeyOverride { modifiers: 0xE002, key: 0xE404, replacement: 0xF000 | 0x4C }];
+        let info = crate::custom_key_codes::extra_information_about_key_codes();
+        let mut overrides = KeyOverrides::new(&RULES);
+        let mut pressed = keys(&[0x04]);
+        assert_eq!(overrides.apply(SHIFT, &mut pressed, false, &info), SHIFT);
+        assert_eq!(&pressed[..], &keys(&[0x04])[..]);
+    }
+}
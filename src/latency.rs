@@ -0,0 +1,108 @@
+//! Input latency measurement. Every key event is timestamped when the change is seen in the
+//! scan, when it has passed the debouncing, and when the USB report containing it is sent. The
+//! delays between these stages are collected in histograms, which can be printed over serial
+//! console. Measurement is toggled with serial command 'm', see `console`.
+
+/// Width of one histogram bin (in microseconds)
+const BIN_WIDTH: u32 = 25;
+/// Number of bins. The last bin contains also everything that is longer.
+const N_BINS: usize = 80;
+
+/// Statistics of one latency, e.g. from debouncing to sending
+pub struct LatencyStats {
+    pub min: u32,
+    pub max: u32,
+    pub sum: u64,
+    pub count: u32,
+    histogram: [u32; N_BINS],
+}
+
+impl LatencyStats {
+    pub fn new() -> LatencyStats {
+        LatencyStats { min: u32::MAX, max: 0, sum: 0, count: 0, histogram: [0; N_BINS] }
+    }
+
+    /// Add one measured latency (in microseconds)
+    pub fn record(&mut self, latency: u32) {
+        self.min = u32::min(self.min, latency);
+        self.max = u32::max(self.max, latency);
+        self.sum += latency as u64;
+        self.count += 1;
+        let bin = usize::min((latency / BIN_WIDTH) as usize, N_BINS - 1);
+        self.histogram[bin] += 1;
+    }
+
+    pub fn mean(&self) -> u32 {
+        return if self.count > 0 { (self.sum / self.count as u64) as u32 } else { 0 };
+    }
+
+    /// Latency under which `percent` of all measured latencies are. The resolution is limited to
+    /// the bin width, so the upper bound of the bin is returned.
+    pub fn percentile(&self, percent: u32) -> u32 {
+        let limit = (self.count as u64 * percent as u64 + 99) / 100;
+        let mut cumulative = 0;
+        for (i, &n) in self.histogram.iter().enumerate() {
+            cumulative += n as u64;
+            if cumulative >= limit {
+                return u32::min((i as u32 + 1) * BIN_WIDTH, self.max);
+            }
+        }
+        return self.max;
+    }
+
+    /// Print statistics and non-empty histogram bins
+    pub fn print(&self, name: &str) {
+        if self.count == 0 {
+            println!("{}: no measurements", name);
+            return;
+        }
+        println!("{}: min {} us, mean {} us, p99 {} us, max {} us, count {}",
+                 name, self.min, self.mean(), self.percentile(99), self.max, self.count);
+        for (i, &n) in self.histogram.iter().enumerate().filter(|(_, &n)| n > 0) {
+            let lower = i as u32 * BIN_WIDTH;
+            if i == N_BINS - 1 {
+                println!("    {:>5}+      us: {}", lower, n);
+            } else {
+                println!("    {:>5}-{:<5} us: {}", lower, lower + BIN_WIDTH, n);
+            }
+        }
+    }
+}
+
+/// Latencies of all stages between a key state change and the USB report
+pub struct LatencyMeter {
+    /// From scan to passing the debounce
+    pub scan_to_debounce: LatencyStats,
+    /// From passing the debounce to sending the report
+    pub debounce_to_send: LatencyStats,
+    /// From scan to sending the report, i.e. the total latency
+    pub scan_to_send: LatencyStats,
+}
+
+impl LatencyMeter {
+    pub fn new() -> LatencyMeter {
+        LatencyMeter {
+            scan_to_debounce: LatencyStats::new(),
+            debounce_to_send: LatencyStats::new(),
+            scan_to_send: LatencyStats::new(),
+        }
+    }
+
+    /// Record latencies of one event. All times are in microseconds, and they may wrap around.
+    /// # Arguments
+    /// * `scanned`   Time when the change was seen in scan
+    /// * `debounced` Time when the change passed debouncing
+    /// * `sent`      Time when the report was sent
+    pub fn record(&mut self, scanned: u32, debounced: u32, sent: u32) {
+        self.scan_to_debounce.record(debounced.wrapping_sub(scanned));
+        self.debounce_to_send.record(sent.wrapping_sub(debounced));
+        self.scan_to_send.record(sent.wrapping_sub(scanned));
+    }
+
+    pub fn print(&self) {
+        println!("Input latencies:");
+        self.scan_to_debounce.print("Scan to debounce");
+        self.debounce_to_send.print("Debounce to send");
+        self.scan_to_send.print("Scan to send (total)");
+    }
+}
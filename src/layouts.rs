@@ -0,0 +1,130 @@
+//! Alternative letter layouts, like Dvorak and Colemak, done in firmware. Debounced key events
+//! are remapped before any other stage, so the host can keep its own layout, and the keyboard
+//! works the same on any computer. Key positions are untouched, so statistics still follow the
+//! physical keys.
+//!
+//! Layout is selected with keys from `layout_key`, and the selected layout is saved to EEPROM.
+//! By default, leader sequences K Q, K D and K C select Qwerty, Dvorak and Colemak, see
+//! `LEADER_SEQUENCES` in `custom_key_codes.rs`.
+//! Optionally keys pressed while holding a shortcut modifier keep their QWERTY codes, so that
+//! e.g. Ctrl-C stays where C was.
+
+use crate::eeprom;
+use crate::events::KeyEvent;
+use crate::process_keys::{ExtraKeyInfo, KeyCode, KeyPos};
+use crate::selection::{selection_key, Selection};
+use crate::ShortVec;
+use heapless::Vec; // fixed capacity `std::Vec`
+
+/// Byte mask of layout selection keys, i.e. the second byte of their key code
+pub const LAYOUT_KEY_MASK: u8 = 0xEE;
+
+/// Recognizes saved layout in EEPROM
+const MAGIC: u8 = 0x1A;
+
+/// Letter layout
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Layout {
+    Qwerty = 0,
+    Dvorak = 1,
+    Colemak = 2,
+}
+
+/// Layouts in the order of their discriminants
+const LAYOUTS: [Layout; 3] = [Layout::Qwerty, Layout::Dvorak, Layout::Colemak];
+
+/// Key code that selects layout
+pub const fn layout_key(layout: Layout) -> u32 {
+    return selection_key(LAYOUT_KEY_MASK, layout as u8);
+}
+
+/// Selected layout and remappings of all layouts
+#[derive(Debug)]
+pub struct Layouts {
+    pub layout: Selection<Layout>,
+    /// Remappings (QWERTY key, replacement) of Qwerty, Dvorak and Colemak, in this order
+    maps: &'static [&'static [(u32, u32)]; 3],
+    /// Modifiers whose shortcuts are kept on QWERTY positions, e.g. `MODIFIERKEY_LEFT_CTRL`.
+    /// Zero remaps shortcuts too.
+    shortcut_modifiers: u32,
+    /// Key codes of pressed keys, decided when the keys were pressed
+    pressed: ShortVec<(KeyPos, u32)>,
+}
+
+impl Layouts {
+    /// Create layouts, and load the selected layout from EEPROM
+    pub fn new(maps: &'static [&'static [(u32, u32)]; 3], shortcut_modifiers: u32) -> Layouts {
+        let layout =
+            Selection::load("Layout", &LAYOUTS, LAYOUT_KEY_MASK, eeprom::LAYOUT_ADDR, MAGIC);
+        return Layouts { layout, maps, shortcut_modifiers, pressed: Vec::new() };
+    }
+
+    /// Remap key code of debounced event according to the selected layout. Code of a key is
+    /// decided when it is pressed, and kept until it is released, so that the layout or a
+    /// shortcut modifier does not change keys that are held.
+    pub fn remap(&mut self, event: &mut KeyEvent, info: &ExtraKeyInfo) {
+        let code = event.code.into_inner();
+        let idx = self.pressed.iter().position(|&(pos, _)| pos == event.pos);
+        let shortcut = self.pressed.iter().any(|&(_, c)| {
+            info.is_modifier(c) && c & self.shortcut_modifiers & 0xFF != 0
+        });
+        let remapped = match idx {
+            Some(i) => self.pressed[i].1,
+            None if shortcut => code,
+            None => self.maps[self.layout.index()].iter()
+                .find(|&&(from, _)| from == code)
+                .map_or(code, |&(_, to)| to),
+        };
+        event.code = match event.code {
+            KeyCode::Certain(_) => KeyCode::Certain(remapped),
+            KeyCode::Uncertain(_) => KeyCode::Uncertain(remapped),
+        };
+        match (idx, event.pressed) {
+            (None, true) => self.pressed.push((event.pos, remapped)).unwrap_or(()),
+            (Some(i), false) => { self.pressed.swap_remove(i); }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::testing::key_event;
+    use teensy3::bindings as b;
+
+    const DVORAK: &[(u32, u32)] = &[(b::KEY_C, b::KEY_J), (b::KEY_J, b::KEY_H)];
+    const MAPS: [&[(u32, u32)]; 3] = [&[], DVORAK, &[]];
+    const CTRL: u32 = b::MODIFIERKEY_LEFT_CTRL;
+
+    /// Remap event, and return its key code
+    fn remap(layouts: &mut Layouts, code: u32, pressed: bool) -> u32 {
+        let info = crate::custom_key_codes::extra_information_about_key_codes();
+        let mut event = key_event(code, pressed, 0);
+        layouts.remap(&mut event, &info);
+        return event.code.into_inner();
+    }
+
+    #[test]
+    fn held_key_keeps_code_until_released() {
+        let mut layouts = Layouts::new(&MAPS, CTRL);
+        layouts.layout.select(layout_key(Layout::Dvorak));
+        assert_eq!(remap(&mut layouts, b::KEY_C, true), b::KEY_J);
+        layouts.layout.select(layout_key(Layout::Qwerty));
+        assert_eq!(remap(&mut layouts, b::KEY_C, false), b::KEY_J);
+        assert_eq!(remap(&mut layouts, b::KEY_C, true), b::KEY_C);
+    }
+
+    #[test]
+    fn shortcuts_stay_on_qwerty_positions() {
+        let mut layouts = Layouts::new(&MAPS, CTRL);
+        layouts.layout.select(layout_key(Layout::Dvorak));
+        assert_eq!(remap(&mut layouts, b::KEY_J, true), b::KEY_H);
+        assert_eq!(remap(&mut layouts, CTRL, true), CTRL);
+        assert_eq!(remap(&mut layouts, b::KEY_C, true), b::KEY_C);
+        // J was pressed before Ctrl
+        assert_eq!(remap(&mut layouts, b::KEY_J, false), b::KEY_H);
+        assert_eq!(remap(&mut layouts, CTRL, false), CTRL);
+        assert_eq!(remap(&mut layouts, b::KEY_C, false), b::KEY_C);
+    }
+}
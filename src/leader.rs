@@ -0,0 +1,281 @@
+//! Leader key. After leader key is pressed, a short sequence of keys triggers an action, e.g.
+//! Leader, G, S could type a greeting. Keys of the sequence are not sent to the host, even if the
+//! sequence is unknown or it times out.
+//!
+//! Sequences are defined in `custom_key_codes.rs`, and they are stored in a trie, so that every
+//! key press of the sequence is matched by looking at the children of the current node. Leader
+//! key itself is `LEADER_KEY`.
+
+use heapless::Vec; // fixed capacity `std::Vec`
+use typenum::U64 as NodesCap; // Maximum number of nodes in trie
+
+use crate::events::{push_warn, EventVec, KeyEvent, LEADER_MATRIX};
+use crate::process_keys::{ExtraKeyInfo, KeyPos};
+use crate::ShortVec;
+
+/// Key code of leader key
+pub const LEADER_KEY: u32 = 0xEB00;
+
+/// What happens when sequence is typed
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LeaderAction {
+    /// Tap key. It may be also macro key, see `macros::macro_key`.
+    Key(u32),
+    /// Toggle Fn layer on or off
+    ToggleFnLayer,
+    /// Tap media or system key, e.g. `b::KEY_MEDIA_MUTE`
+    SystemKey(u32),
+}
+
+/// Node of trie. Children are stored as a linked list: node points to its first child, which
+/// points to its next sibling, and so on.
+#[derive(Debug, Copy, Clone)]
+struct Node {
+    code: u32,
+    first_child: Option<u8>,
+    next_sibling: Option<u8>,
+    action: Option<LeaderAction>,
+}
+
+/// Leader key stage of key events
+#[derive(Debug)]
+pub struct Leader {
+    /// Sequence is ended if next key is not pressed within this time (in microseconds)
+    pub timeout: u32,
+    /// Trie of sequences. The first node is the root.
+    nodes: Vec<Node, NodesCap>,
+    /// Current node in trie, if sequence is being typed
+    current: Option<usize>,
+    /// Time of the previous key press of sequence (in microseconds)
+    since: u32,
+    /// Keys whose events are not passed on, until they are released
+    swallowed: ShortVec<KeyPos>,
+    /// Tapped key that is released in the next report
+    tapped: Option<u32>,
+    /// System key that is waiting to be sent
+    system_key: Option<u32>,
+    fn_layer: bool,
+    layer_changed: bool,
+}
+
+impl Leader {
+    pub fn new(timeout: u32) -> Leader {
+        let root = Node { code: 0, first_child: None, next_sibling: None, action: None };
+        let mut nodes = Vec::new();
+        nodes.push(root).unwrap();
+        return Leader {
+            timeout,
+            nodes,
+            current: None,
+            since: 0,
+            swallowed: Vec::new(),
+            tapped: None,
+            system_key: None,
+            fn_layer: false,
+            layer_changed: false,
+        };
+    }
+
+    /// Add sequence of key codes to trie. Returns `Err` if the trie is full.
+    pub fn add(&mut self, sequence: &[u32], action: LeaderAction) -> Result<(), ()> {
+        let mut node = 0;
+        for &code in sequence.iter() {
+            node = match self.child(node, code) {
+                Some(child) => child,
+                None => {
+                    let next_sibling = self.nodes[node].first_child;
+                    let child = Node { code, first_child: None, next_sibling, action: None };
+                    self.nodes.push(child).map_err(|_| ())?;
+                    let idx = self.nodes.len() - 1;
+                    self.nodes[node].first_child = Some(idx as u8);
+                    idx
+                }
+            };
+        }
+        self.nodes[node].action = Some(action);
+        return Ok(());
+    }
+
+    fn child(&self, node: usize, code: u32) -> Option<usize> {
+        let mut child = self.nodes[node].first_child;
+        while let Some(idx) = child.map(|c| c as usize) {
+            if self.nodes[idx].code == code {
+                return Some(idx);
+            }
+            child = self.nodes[idx].next_sibling;
+        }
+        return None;
+    }
+
+    /// Pass event through leader stage. Resulting events are pushed to `out`.
+    pub fn process(&mut self, event: KeyEvent, info: &ExtraKeyInfo, out: &mut EventVec) {
+        let code = event.code.into_inner();
+        if let Some(i) = self.swallowed.iter().position(|&pos| pos == event.pos) {
+            if !event.pressed {
+                self.swallowed.swap_remove(i);
+            }
+            return;
+        }
+        if event.pressed && code == LEADER_KEY {
+            self.current = Some(0);
+            self.since = event.time;
+            self.swallowed.push(event.pos).unwrap_or(());
+            return;
+        }
+        let node = match self.current {
+            Some(node) if event.pressed => node,
+            _ => {
+                push_warn(out, event);
+                return;
+            }
+        };
+        self.swallowed.push(event.pos).unwrap_or(());
+        let is_modifier = info.is_modifier(code);
+        if is_modifier {
+            return;
+        }
+        match self.child(node, code) {
+            Some(child) if self.nodes[child].first_child.is_none() => self.finish(child, out),
+            Some(child) => {
+                // Longer sequences are possible, so wait for the next key
+                self.current = Some(child);
+                self.since = event.time;
+            }
+            None => {
+                println!("Unknown leader sequence");
+                self.current = None;
+            }
+        }
+    }
+
+    /// Release the tapped key of the previous report, and end the sequence if its time is up.
+    /// This should be called once per report, before `process`.
+    /// # Arguments
+    /// * `now` Current time (in microseconds)
+    /// * `out` Resulting events are pushed here
+    pub fn update(&mut self, now: u32, out: &mut EventVec) {
+        if let Some(code) = self.tapped.take() {
+            push_warn(out, self.event(code, false, now));
+        }
+        if let Some(node) = self.current {
+            if now.wrapping_sub(self.since) >= self.timeout {
+                self.finish(node, out);
+            }
+        }
+    }
+
+    /// End sequence at trie node, and do its action if there is any
+    fn finish(&mut self, node: usize, out: &mut EventVec) {
+        self.current = None;
+        match self.nodes[node].action {
+            Some(LeaderAction::Key(code)) => {
+                push_warn(out, self.event(code, true, self.since));
+                self.tapped = Some(code);
+            }
+            Some(LeaderAction::ToggleFnLayer) => {
+                self.fn_layer = !self.fn_layer;
+                self.layer_changed = true;
+                println!("Fn layer {}", if self.fn_layer { "on" } else { "off" });
+            }
+            Some(LeaderAction::SystemKey(code)) => self.system_key = Some(code),
+            None => {}
+        }
+    }
+
+    fn event(&self, code: u32, pressed: bool, time: u32) -> KeyEvent {
+        return KeyEvent::generated(KeyPos::new(LEADER_MATRIX, 0, 0), code, pressed, time);
+    }
+
+    /// Whether Fn layer is toggled on
+    pub fn fn_layer(&self) -> bool {
+        return self.fn_layer;
+    }
+
+    /// Returns true once after Fn layer is toggled
+    pub fn take_layer_change(&mut self) -> bool {
+        return core::mem::replace(&mut self.layer_changed, false);
+    }
+
+    /// System key that should be tapped, if any
+    pub fn take_system_key(&mut self) -> Option<u32> {
+        return self.system_key.take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::testing::{self, key_event};
+    use teensy3::bindings as b;
+
+    const TIMEOUT: u32 = 1_000_000;
+
+    fn leader() -> Leader {
+        let mut leader = Leader::new(TIMEOUT);
+        leader.add(&[b::KEY_G, b::KEY_S], LeaderAction::Key(b::KEY_1)).unwrap();
+        leader.add(&[b::KEY_G, b::KEY_T], LeaderAction::Key(b::KEY_2)).unwrap();
+        leader.add(&[b::KEY_M], LeaderAction::Key(b::KEY_3)).unwrap();
+        leader.add(&[b::KEY_M, b::KEY_M], LeaderAction::Key(b::KEY_4)).unwrap();
+        return leader;
+    }
+
+    /// Tap leader key and then `codes`, 10 ms apart, and return the resulting (key code, pressed)
+    /// pairs until `end`
+    fn type_sequence(leader: &mut Leader, codes: &[u32], end: u32) -> std::vec::Vec<(u32, bool)> {
+        let info = crate::custom_key_codes::extra_information_about_key_codes();
+        let events: std::vec::Vec<_> = [LEADER_KEY].iter().chain(codes.iter()).enumerate()
+            .map(|(i, &code)| (code, 10_000 * i as u32))
+            .flat_map(|(code, t)| vec![key_event(code, true, t), key_event(code, false, t + 5000)])
+            .collect();
+        let out = testing::run(leader, &events, end,
+                               |l, now, out| l.update(now, out),
+                               |l, e, out| l.process(e, &info, out));
+        return out.iter().map(|e| (e.code.into_inner(), e.pressed)).collect();
+    }
+
+    #[test]
+    fn sequences_share_prefix_in_trie() {
+        let leader = leader();
+        // Root, G, S, T, M and M
+        assert_eq!(leader.nodes.len(), 6);
+        let g = leader.child(0, b::KEY_G).unwrap();
+        assert!(leader.child(g, b::KEY_S).is_some() && leader.child(g, b::KEY_T).is_some());
+        assert_eq!(leader.child(0, b::KEY_S), None);
+    }
+
+    #[test]
+    fn complete_sequence_taps_key() {
+        assert_eq!(type_sequence(&mut leader(), &[b::KEY_G, b::KEY_T], 50_000),
+                   [(b::KEY_2, true), (b::KEY_2, false)]);
+    }
+
+    #[test]
+    fn prefix_sequence_is_resolved_on_timeout() {
+        let out = type_sequence(&mut leader(), &[b::KEY_M], TIMEOUT);
+        assert!(out.is_empty());
+        let out = type_sequence(&mut leader(), &[b::KEY_M], TIMEOUT + 20_000);
+        assert_eq!(out, [(b::KEY_3, true), (b::KEY_3, false)]);
+        // The longer sequence does not wait
+        let out = type_sequence(&mut leader(), &[b::KEY_M, b::KEY_M], 50_000);
+        assert_eq!(out, [(b::KEY_4, true), (b::KEY_4, false)]);
+    }
+
+    #[test]
+    fn unknown_sequence_is_swallowed() {
+        let mut leader = leader();
+        assert!(type_sequence(&mut leader, &[b::KEY_G, b::KEY_X], TIMEOUT + 50_000).is_empty());
+        // Sequence has ended, so next keys pass through
+        let info = crate::custom_key_codes::extra_information_about_key_codes();
+        let mut out = EventVec::new();
+        leader.process(key_event(b::KEY_S, true, 2 * TIMEOUT), &info, &mut out);
+        assert_eq!(out[0].code.into_inner(), b::KEY_S);
+    }
+
+    #[test]
+    fn modifiers_do_not_break_sequence() {
+        let codes = [b::KEY_G, b::MODIFIERKEY_LEFT_SHIFT, b::KEY_S];
+        assert_eq!(type_sequence(&mut leader(), &codes, 50_000),
+                   [(b::KEY_1, true), (b::KEY_1, false)]);
+    }
+}
@@ -0,0 +1,376 @@
+//! Macro engine. Macro key plays a sequence of key presses, releases, delays and text strings.
+//! The sequence is emitted over successive USB reports, one change per report, so that the six
+//! key slots are never overflowed, and the host sees every press and release.
+//!
+//! Macro keys are defined in `custom_key_codes.rs` like any other key, with codes from
+//! `macro_key`. Their second byte is `ExtraKeyInfo::macro_key_mask`.
+
+use heapless::Vec; // fixed capacity `std::Vec`
+use typenum::U4;
+pub use typenum::U64 as RecordCap; // Maximum length of recorded macro, see `dynamic_macro`
+
+use teensy3::bindings as b;
+
+use crate::process_keys::ExtraKeyInfo;
+
+/// One step of a macro
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MacroStep {
+    /// Press regular key or modifier, e.g. `Press(b::MODIFIERKEY_LEFT_CTRL)`
+    Press(u32),
+    /// Release key that was pressed with `Press`
+    Release(u32),
+    /// Wait some milliseconds
+    Delay(u32),
+    /// Type text. Characters are translated with the host keyboard layout, see `char_to_key`.
+    Type(&'static str),
+}
+
+/// Macro is just a sequence of steps
+pub type Macro = &'static [MacroStep];
+
+/// Steps of the macro that is playing. There is no heap, so recorded macro is copied inline.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+enum Steps {
+    /// Macro from configuration
+    Static(Macro),
+    /// Copy of recorded dynamic macro
+    Recorded(Vec<MacroStep, RecordCap>),
+}
+
+impl Steps {
+    fn as_slice(&self) -> &[MacroStep] {
+        return match self {
+            Steps::Static(steps) => steps,
+            Steps::Recorded(steps) => steps,
+        };
+    }
+}
+
+/// Key code of n:th macro key, see `ExtraKeyInfo::macros`
+pub const fn macro_key(index: u8) -> u32 {
+    return 0xE900 | index as u32;
+}
+
+/// Macro of the key code, if it is a macro key
+pub fn macro_of(code: u32, info: &ExtraKeyInfo) -> Option<Macro> {
+    let bytes = code.to_le_bytes();
+    if bytes[1] != info.macro_key_mask {
+        return None;
+    }
+    return info.macros.get(bytes[0] as usize).copied();
+}
+
+/// State of keys as it is sent over USB
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Report {
+    /// Modifier key bits, i.e. the lowest byte of modifier key codes
+    pub modifiers: u16,
+    /// Regular keys
+    pub keys: [Option<u8>; 6],
+}
+
+/// Report where nothing is pressed
+const RELEASED: Report = Report { modifiers: 0, keys: [None; 6] };
+
+/// Plays one macro at a time. While macro is playing, its reports replace the keys that are
+/// physically pressed.
+#[derive(Debug)]
+pub struct MacroPlayer {
+    steps: Steps,
+    /// Index of the current step
+    step: usize,
+    /// Byte index of the next character, if the current step is `Type`
+    char_idx: usize,
+    /// Keys that macro has pressed with `Press`
+    pressed: Report,
+    /// Reports of the current character, in reversed order
+    pending: Vec<Report, U4>,
+    /// `Delay` is running until this time (in microseconds)
+    wait_until: Option<u32>,
+}
+
+impl MacroPlayer {
+    pub fn new() -> MacroPlayer {
+        return MacroPlayer {
+            steps: Steps::Static(&[]),
+            step: 0,
+            char_idx: 0,
+            pressed: RELEASED,
+            pending: Vec::new(),
+            wait_until: None,
+        };
+    }
+
+    /// Start playing macro. Previous macro is interrupted if it is still playing.
+    pub fn start(&mut self, steps: Macro) {
+        *self = MacroPlayer { steps: Steps::Static(steps), ..MacroPlayer::new() };
+    }
+
+    /// Start playing recorded macro, see `dynamic_macro`
+    pub fn start_recorded(&mut self, steps: Vec<MacroStep, RecordCap>) {
+        *self = MacroPlayer { steps: Steps::Recorded(steps), ..MacroPlayer::new() };
+    }
+
+    /// Whether there is something left to send
+    pub fn is_playing(&self) -> bool {
+        return self.step < self.steps.as_slice().len()
+            || !self.pending.is_empty()
+            || self.wait_until.is_some()
+            || self.pressed != RELEASED;
+    }
+
+    /// Next report to send, called once per USB report. Returns None if macro is waiting for
+    /// delay, or if it is finished. In the end all keys that are pressed by macro are released.
+    /// # Arguments
+    /// * `now` Current time (in microseconds)
+    pub fn next_report(&mut self, now: u32) -> Option<Report> {
+        if let Some(report) = self.pending.pop() {
+            return Some(report);
+        }
+        if let Some(t) = self.wait_until {
+            if (now.wrapping_sub(t) as i32) < 0 {
+                return None;
+            }
+            self.wait_until = None;
+        }
+        while let Some(&step) = self.steps.as_slice().get(self.step) {
+            match step {
+                MacroStep::Press(code) => {
+                    self.step += 1;
+                    press(&mut self.pressed, code);
+                    return Some(self.pressed);
+                }
+                MacroStep::Release(code) => {
+                    self.step += 1;
+                    release(&mut self.pressed, code);
+                    return Some(self.pressed);
+                }
+                MacroStep::Delay(ms) => {
+                    self.step += 1;
+                    self.wait_until = Some(now.wrapping_add(ms * 1000));
+                    return None;
+                }
+                MacroStep::Type(text) => {
+                    let c = match text[self.char_idx..].chars().next() {
+                        Some(c) => c,
+                        None => {
+                            self.step += 1;
+                            self.char_idx = 0;
+                            continue;
+                        }
+                    };
+                    self.char_idx += c.len_utf8();
+                    match char_to_key(c) {
+                        Some(typed) => {
+                            self.pending = self.typing_reports(typed);
+                            return self.pending.pop();
+                        }
+                        None => println!("Warning! Macro can not type character '{}'", c),
+                    }
+                }
+            }
+        }
+        // Release everything that is left pressed
+        if self.pressed != RELEASED {
+            self.pressed = RELEASED;
+            return Some(self.pressed);
+        }
+        return None;
+    }
+
+    /// Reports that type one character, in reversed order. Dead keys (e.g. '^') are followed by
+    /// space, so that the host does not combine them with the next character.
+    fn typing_reports(&self, (modifiers, key, dead): (u16, u8, bool)) -> Vec<Report, U4> {
+        let mut down = self.pressed;
+        down.modifiers |= modifiers;
+        press(&mut down, key as u32 | 0xF000);
+        let mut space = self.pressed;
+        press(&mut space, b::KEY_SPACE);
+        let mut reports = Vec::new();
+        if dead {
+            reports.push(self.pressed).unwrap();
+            reports.push(space).unwrap();
+        }
+        reports.push(self.pressed).unwrap();
+        reports.push(down).unwrap();
+        return reports;
+    }
+}
+
+fn press(report: &mut Report, code: u32) {
+    if code & 0xFF00 == 0xE000 {
+        report.modifiers |= code as u16 & 0xFF;
+    } else if !report.keys.contains(&Some(code as u8)) {
+        if let Some(slot) = report.keys.iter_mut().find(|s| s.is_none()) {
+            *slot = Some(code as u8);
+        }
+    }
+}
+
+fn release(report: &mut Report, code: u32) {
+    if code & 0xFF00 == 0xE000 {
+        report.modifiers &= !(code as u16 & 0xFF);
+    } else {
+        report.keys.iter_mut().filter(|s| **s == Some(code as u8)).for_each(|s| *s = None);
+    }
+}
+
+const SHIFT: u16 = b::MODIFIERKEY_LEFT_SHIFT as u16 & 0xFF;
+const ALTGR: u16 = b::MODIFIERKEY_RIGHT_ALT as u16 & 0xFF;
+
+/// Characters that are not letters or digits, in Finnish keyboard layout. The tuple is
+/// (character, modifiers, key, is dead key).
+const FINNISH_SYMBOLS: &[(char, u16, u32, bool)] = &[
+    (' ', 0, b::KEY_SPACE, false), ('\n', 0, b::KEY_ENTER, false), ('\t', 0, b::KEY_TAB, false),
+    ('!', SHIFT, b::KEY_1, false), ('"', SHIFT, b::KEY_2, false), ('#', SHIFT, b::KEY_3, false),
+    ('¤', SHIFT, b::KEY_4, false), ('%', SHIFT, b::KEY_5, false), ('&', SHIFT, b::KEY_6, false),
+    ('/', SHIFT, b::KEY_7, false), ('(', SHIFT, b::KEY_8, false), (')', SHIFT, b::KEY_9, false),
+    ('=', SHIFT, b::KEY_0, false), ('@', ALTGR, b::KEY_2, false), ('£', ALTGR, b::KEY_3, false),
+    ('$', ALTGR, b::KEY_4, false), ('€', ALTGR, b::KEY_E, false), ('{', ALTGR, b::KEY_7, false),
+    ('[', ALTGR, b::KEY_8, false), (']', ALTGR, b::KEY_9, false), ('}', ALTGR, b::KEY_0, false),
+    ('+', 0, b::KEY_MINUS, false), ('?', SHIFT, b::KEY_MINUS, false),
+    ('\\', ALTGR, b::KEY_MINUS, false), ('´', 0, b::KEY_EQUAL, true),
+    ('`', SHIFT, b::KEY_EQUAL, true), ('å', 0, b::KEY_LEFT_BRACE, false),
+    ('Å', SHIFT, b::KEY_LEFT_BRACE, false), ('¨', 0, b::KEY_RIGHT_BRACE, true),
+    ('^', SHIFT, b::KEY_RIGHT_BRACE, true), ('~', ALTGR, b::KEY_RIGHT_BRACE, true),
+    ('ö', 0, b::KEY_SEMICOLON, false), ('Ö', SHIFT, b::KEY_SEMICOLON, false),
+    ('ä', 0, b::KEY_QUOTE, false), ('Ä', SHIFT, b::KEY_QUOTE, false),
+    ('\'', 0, b::KEY_BACKSLASH, false), ('*', SHIFT, b::KEY_BACKSLASH, false),
+    ('§', 0, b::KEY_TILDE, false), ('½', SHIFT, b::KEY_TILDE, false),
+    ('<', 0, b::KEY_NON_US_BS, false), ('>', SHIFT, b::KEY_NON_US_BS, false),
+    ('|', ALTGR, b::KEY_NON_US_BS, false), (',', 0, b::KEY_COMMA, false),
+    (';', SHIFT, b::KEY_COMMA, false), ('.', 0, b::KEY_PERIOD, false),
+    (':', SHIFT, b::KEY_PERIOD, false), ('-', 0, b::KEY_SLASH, false),
+    ('_', SHIFT, b::KEY_SLASH, false), ('µ', ALTGR, b::KEY_M, false),
+];
+
+/// Translate character to (modifiers, key, is dead key). The host is expected to use the same
+/// keyboard layout as teensy3 is built with, i.e. the `layout_finnish` feature in `Cargo.toml`.
+/// If the layout is changed, this function must be changed too.
+pub fn char_to_key(c: char) -> Option<(u16, u8, bool)> {
+    let a = b::KEY_A as u8;
+    return match c {
+        'a'..='z' => Some((0, a + (c as u8 - b'a'), false)),
+        'A'..='Z' => Some((SHIFT, a + (c as u8 - b'A'), false)),
+        '1'..='9' => Some((0, b::KEY_1 as u8 + (c as u8 - b'1'), false)),
+        '0' => Some((0, b::KEY_0 as u8, false)),
+        _ => FINNISH_SYMBOLS.iter()
+            .find(|&&(symbol, _, _, _)| symbol == c)
+            .map(|&(_, modifiers, key, dead)| (modifiers, key as u8, dead)),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use MacroStep::*;
+
+    /// Report with given modifier bits and keys
+    fn report(modifiers: u16, keys: &[u32]) -> Report {
+        let mut r = Report { modifiers, keys: [None; 6] };
+        keys.iter().for_each(|&k| press(&mut r, k));
+        return r;
+    }
+
+    /// Play macro to the end without delays, and collect the reports
+    fn play(steps: Macro) -> std::vec::Vec<Report> {
+        let mut player = MacroPlayer::new();
+        player.start(steps);
+        let mut reports = std::vec::Vec::new();
+        let mut now = 0;
+        while player.is_playing() {
+            if let Some(r) = player.next_report(now) {
+                reports.push(r);
+            }
+            now += 1000;
+        }
+        return reports;
+    }
+
+    const CTRL: u16 = b::MODIFIERKEY_LEFT_CTRL as u16 & 0xFF;
+
+    #[test]
+    fn press_and_release_one_change_per_report() {
+        let steps: Macro = &[
+            Press(b::MODIFIERKEY_LEFT_CTRL), Press(b::KEY_C), Release(b::KEY_C),
+            Release(b::MODIFIERKEY_LEFT_CTRL),
+        ];
+        assert_eq!(play(steps), [
+            report(CTRL, &[]), report(CTRL, &[b::KEY_C]), report(CTRL, &[]), report(0, &[]),
+        ]);
+    }
+
+    #[test]
+    fn keys_left_pressed_are_released_in_the_end() {
+        let steps: Macro = &[Press(b::KEY_A), Press(b::MODIFIERKEY_LEFT_SHIFT)];
+        assert_eq!(play(steps), [
+            report(0, &[b::KEY_A]), report(SHIFT, &[b::KEY_A]), RELEASED,
+        ]);
+    }
+
+    #[test]
+    fn type_letters_digits_and_shifted_symbols() {
+        assert_eq!(play(&[Type("aB1!")]), [
+            report(0, &[b::KEY_A]), RELEASED,
+            report(SHIFT, &[b::KEY_B]), RELEASED,
+            report(0, &[b::KEY_1]), RELEASED,
+            report(SHIFT, &[b::KEY_1]), RELEASED,
+        ]);
+    }
+
+    #[test]
+    fn type_euro_with_altgr() {
+        assert_eq!(play(&[Type("€")]), [report(ALTGR, &[b::KEY_E]), RELEASED]);
+    }
+
+    #[test]
+    fn dead_keys_are_followed_by_space() {
+        assert_eq!(play(&[Type("^~a")]), [
+            report(SHIFT, &[b::KEY_RIGHT_BRACE]), RELEASED,
+            report(0, &[b::KEY_SPACE]), RELEASED,
+            report(ALTGR, &[b::KEY_RIGHT_BRACE]), RELEASED,
+            report(0, &[b::KEY_SPACE]), RELEASED,
+            report(0, &[b::KEY_A]), RELEASED,
+        ]);
+    }
+
+    #[test]
+    fn typing_keeps_keys_pressed_by_macro() {
+        let steps: Macro = &[
+            Press(b::MODIFIERKEY_LEFT_CTRL), Type("ä"), Release(b::MODIFIERKEY_LEFT_CTRL),
+        ];
+        assert_eq!(play(steps), [
+            report(CTRL, &[]), report(CTRL, &[b::KEY_QUOTE]), report(CTRL, &[]), RELEASED,
+        ]);
+    }
+
+    #[test]
+    fn unknown_characters_are_skipped() {
+        assert_eq!(play(&[Type("a\u{263a}b")]), [
+            report(0, &[b::KEY_A]), RELEASED, report(0, &[b::KEY_B]), RELEASED,
+        ]);
+    }
+
+    #[test]
+    fn delay_waits_before_next_step() {
+        let mut player = MacroPlayer::new();
+        player.start(&[Press(b::KEY_A), Delay(10), Release(b::KEY_A)]);
+        assert_eq!(player.next_report(0), Some(report(0, &[b::KEY_A])));
+        assert_eq!(player.next_report(1_000), None);
+        assert_eq!(player.next_report(10_999), None);
+        assert!(player.is_playing());
+        assert_eq!(player.next_report(11_000), Some(RELEASED));
+        assert_eq!(player.next_report(12_000), None);
+        assert!(!player.is_playing());
+    }
+
+    #[test]
+    fn macro_key_codes() {
+        assert_eq!(macro_key(3), 0xE903);
+        assert_eq!(char_to_key('0'), Some((0, b::KEY_0 as u8, false)));
+        assert_eq!(char_to_key('¨'), Some((0, b::KEY_RIGHT_BRACE as u8, true)));
+    }
+}
@@ -1,14 +1,37 @@
-#![no_std]
-#![no_main]
+// Unit tests are run on the host, where the standard library is available
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 #![deny(unused_must_use)]
 #![allow(clippy::needless_return)]
 
-#[macro_use]
+// Unit tests print with `std::println` instead of USB serial
+#[cfg_attr(not(test), macro_use)]
 extern crate teensy3;
 
+mod auto_shift;
+mod caps_word;
+mod chatter;
+mod combos;
+mod console;
 mod custom_key_codes;
+mod dynamic_macro;
+mod eeprom;
+mod events;
+mod idle;
+mod key_overrides;
+mod layouts;
+mod latency;
+mod leader;
+mod macros;
+mod one_shot;
+mod os_profile;
 mod process_keys;
 mod record_keyboard_matrix;
+mod selection;
+mod stats;
+mod stuck;
+mod tap_dance;
+mod typematic;
 pub use typenum::U24 as MatrixCap; // Maximum side length of keyboard matrix (=24)
 
 use heapless::{ArrayLength, Vec}; // fixed capacity `std::Vec`
@@ -16,9 +39,22 @@ use heapless::{ArrayLength, Vec}; // fixed capacity `std::Vec`
 use teensy3::bindings as b;
 use b::usb_keyboard_class as KBoard;
 use teensy3::pins::{Pin, PinRow};
-use teensy3::util::{delay, MillisTimer};
+use teensy3::util::delay;
 
-use process_keys::{ExtraKeyInfo, KeyCode};
+use auto_shift::AutoShift;
+use caps_word::CapsWord;
+use chatter::ChatterTuner;
+use dynamic_macro::DynamicMacro;
+use idle::{IdleState, PowerMode};
+use latency::LatencyMeter;
+use macros::MacroPlayer;
+use one_shot::OneShot;
+use os_profile::OsProfiles;
+use events::{EventQueue, EventVec};
+use process_keys::{Debouncer, ExtraKeyInfo, KeyCode, KeyMatrices, KeyPos};
+use stats::KeyStats;
+use stuck::StuckKeys;
+use typematic::{Repeat, Typematic};
 
 type ShortVec<T> = Vec<T, MatrixCap>;
 
@@ -50,6 +86,8 @@ enum Key {
     Normal(u8),
     Modifier(u16),
     Fn,
+    /// Keys that are handled before categorization, e.g. macro keys and one-shot keys
+    Special,
 }
 
 fn extract_key_type(key_code: u32, info: &ExtraKeyInfo) -> Key {
@@ -64,17 +102,101 @@ fn extract_key_type(key_code: u32, info: &ExtraKeyInfo) -> Key {
         0xE2 => panic!("System keys not supported here."),
         0xE4 => panic!("Media keys not supported here."),
         m if m == fn_mask => Key::Fn,
+        m if m == info.macro_key_mask
+            || m == one_shot::ONE_SHOT_MASK
+            || m == os_profile::PROFILE_KEY_MASK
+            || m == layouts::LAYOUT_KEY_MASK => Key::Special,
         _ => panic!("Dafuq is that key?"),
     }
 }
 
+/// How to resolve uncertain key presses. If three corners of a rectangle in key matrix are
+/// pressed, the fourth corner reads pressed too, and it is impossible to tell which of the keys
+/// are really pressed. These keys are marked as `KeyCode::Uncertain`.
+///
+/// The corners that were pressed before the rectangle was completed are real. The press that
+/// completes the rectangle and the ghost corner appear in the same scan, so they have the same
+/// press time. Of these two, the one that shares a row or a column with the most recently pressed
+/// earlier corner is taken to be the real one, see `newest_real_press`.
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum UncertainPolicy {
+    /// Uncertain key is kept pressed only if it was already pressed on previous report
+    HoldPrevious,
+    /// Uncertain keys are never registered
+    DropAll,
+    /// Only the most recent real press is registered, and older uncertain keys are released
+    PreferMostRecent,
+    /// Keys that were pressed before the rectangle was completed are registered, and of the two
+    /// newest presses, only the real one
+    TimingBased,
+}
+
+/// Pick the real key of uncertain keys that were pressed at the same time, which happens when a
+/// press completes a rectangle and the ghost corner appears with it. Real key is the one that
+/// shares a row or a column with the most recently pressed earlier corner. Returns None if there
+/// are no earlier corners, or if both or none of the keys share a line with it.
+/// # Arguments
+/// * `uncertain` Positions and press times of uncertain keys
+/// * `latest`    Press time of the newest uncertain keys
+fn newest_real_press(uncertain: &ShortVec<(KeyPos, u32)>, latest: u32) -> Option<KeyPos> {
+    let shares_line = |a: KeyPos, b: KeyPos| {
+        a.matrix == b.matrix && (a.row == b.row || a.col == b.col)
+    };
+    let &(previous, _) = uncertain.iter()
+        .filter(|&&(_, t)| t != latest)
+        .max_by_key(|&&(_, t)| t.wrapping_sub(latest) as i32)?;
+    let mut candidates = uncertain.iter()
+        .filter(|&&(pos, t)| t == latest && shares_line(pos, previous));
+    return match (candidates.next(), candidates.next()) {
+        (Some(&(pos, _)), None) => Some(pos),
+        _ => None,
+    };
+}
+
+/// Decide whether uncertain key is registered as pressed.
+/// # Arguments
+/// * `policy`            Resolution policy
+/// * `pos`               Position of the key
+/// * `registered_before` Whether the key was registered on previous report
+/// * `uncertain`         Positions and press times of all uncertain keys, including this one if
+///                       its press time is known
+fn resolve_uncertain(
+    policy: UncertainPolicy,
+    pos: KeyPos,
+    registered_before: bool,
+    uncertain: &ShortVec<(KeyPos, u32)>,
+) -> bool {
+    let t = match uncertain.iter().find(|&&(p, _)| p == pos) {
+        Some(&(_, t)) => t,
+        None => return policy == UncertainPolicy::HoldPrevious && registered_before,
+    };
+    // Time stamps wrap around, so compare differences
+    let latest = uncertain.iter()
+        .map(|&(_, other)| other)
+        .max_by_key(|&other| other.wrapping_sub(t) as i32)
+        .unwrap_or(t);
+    let newest = || {
+        let simultaneous = uncertain.iter().filter(|&&(_, other)| other == latest).count() > 1;
+        return !simultaneous || newest_real_press(uncertain, latest) == Some(pos);
+    };
+    match policy {
+        UncertainPolicy::HoldPrevious => return registered_before,
+        UncertainPolicy::DropAll => return false,
+        UncertainPolicy::PreferMostRecent => return t == latest && newest(),
+        UncertainPolicy::TimingBased => return t != latest || newest(),
+    }
+}
+
 /// Categorize key presses to regular keys, modifier keys and Fn key. Also crop out those keys
-/// that are unsure and has not been pressed on last time.
+/// that are uncertain, according to the given policy.
 fn categorize_key_presses(
-    scanned_keys: &Option<ShortVec<KeyCode<u32>>>,
+    scanned_keys: &Option<ShortVec<(KeyPos, KeyCode<u32>)>>,
+    press_times: &ShortVec<(KeyPos, u32)>,
     key_slots: &[Option<u8>; 6],
     modifiers_pressed_old: u16,
     fn_pressed_old: bool,
+    policy: UncertainPolicy,
     info: &ExtraKeyInfo,
 ) -> (ShortVec<KeyCode<u8>>, ShortVec<KeyCode<u16>>, bool) {
     let mut regular_keys: ShortVec<KeyCode<u8>> = Vec::new();
@@ -84,8 +206,15 @@ fn categorize_key_presses(
         Some(v) => v,
         None => return (regular_keys, modifier_keys, fn_key),
     };
+    let press_time = |pos: KeyPos| {
+        press_times.iter().find(|&&(p, _)| p == pos).map(|&(_, t)| t)
+    };
+    let uncertain: ShortVec<(KeyPos, u32)> = scanned_keys.iter()
+        .filter(|(_, state)| state.into_option().is_none())
+        .filter_map(|&(pos, _)| press_time(pos).map(|t| (pos, t)))
+        .collect();
     // Now something is pressed
-    for &state in scanned_keys.iter() {
+    for &(pos, state) in scanned_keys.iter() {
         match state {
             KeyCode::Certain(code) => {
                 // Some key is pressed without ambiguities
@@ -99,29 +228,33 @@ fn categorize_key_presses(
                     Key::Fn => {
                         fn_key = true;
                     }
+                    Key::Special => {}
                 }
             }
             KeyCode::Uncertain(code) => {
-                // Now can not be sure whether or not key is really pressed. If key was
-                // previously pressed, it is kept pressing, otherwise it is not registered.
-                match extract_key_type(code, info) {
+                // Now can not be sure whether or not key is really pressed, so it is registered
+                // only if the policy accepts it.
+                let key = extract_key_type(code, info);
+                let registered_before = match key {
+                    Key::Normal(c) => key_slots.iter().any(|s| s.filter(|s| *s == c).is_some()),
+                    Key::Modifier(c) => modifiers_pressed_old == (modifiers_pressed_old | c),
+                    Key::Fn => fn_pressed_old,
+                    Key::Special => false,
+                };
+                if !resolve_uncertain(policy, pos, registered_before, &uncertain) {
+                    continue;
+                }
+                match key {
                     Key::Normal(c) => {
-                        // Add only if key was pressed on previous round
-                        if key_slots.iter().any(|s| s.filter(|s| *s == c).is_some()) {
-                            regular_keys.push(KeyCode::Uncertain(c)).unwrap_or(());
-                        }
+                        regular_keys.push(KeyCode::Uncertain(c)).unwrap_or(());
                     }
                     Key::Modifier(c) => {
-                        // Add only if modifier key was pressed on previous round
-                        if modifiers_pressed_old == (modifiers_pressed_old | c) {
-                            modifier_keys.push(KeyCode::Uncertain(c)).unwrap_or(())
-                        }
+                        modifier_keys.push(KeyCode::Uncertain(c)).unwrap_or(());
                     }
                     Key::Fn => {
-                        if fn_pressed_old {
-                            fn_key = true;
-                        }
+                        fn_key = true;
                     }
+                    Key::Special => {}
                 }
             }
         };
@@ -129,12 +262,76 @@ fn categorize_key_presses(
     return (regular_keys, modifier_keys, fn_key);
 }
 
-/// Write pressed keys to 6 slots that are send over usb.
+/// Find pressed regular keys that did not fit in the six key slots
+/// # Arguments
+/// * `held`         Pressed keys, as from `KeyMatrices::scan_key_press`
+/// * `n_matrices`   Number of key matrices. Keys of virtual matrices (e.g. combos) are skipped,
+///                  because they are not physical keys.
+/// * `scanned_keys` Regular keys from `categorize_key_presses`
+/// * `regular_keys` The same keys after host profile and key overrides have replaced some of them
+/// * `key_slots`    Slots that are sent
+fn dropped_keys(
+    held: &ShortVec<(KeyPos, KeyCode<u32>)>,
+    n_matrices: usize,
+    scanned_keys: &ShortVec<KeyCode<u8>>,
+    regular_keys: &ShortVec<KeyCode<u8>>,
+    key_slots: &[Option<u8>; 6],
+    info: &ExtraKeyInfo,
+) -> ShortVec<KeyPos> {
+    let is_dropped = |c: u8| {
+        // Replacements keep the order of keys, so the slot code is found by index
+        return match scanned_keys.iter().position(|k| k.into_inner() == c) {
+            Some(i) => !key_slots.contains(&Some(regular_keys[i].into_inner())),
+            None => false,
+        };
+    };
+    return held.iter()
+        .filter(|(pos, _)| (pos.matrix as usize) < n_matrices)
+        .filter_map(|&(pos, code)| match code {
+            KeyCode::Certain(c) => match extract_key_type(c, info) {
+                Key::Normal(c) if is_dropped(c) => Some(pos),
+                _ => None,
+            },
+            KeyCode::Uncertain(_) => None,
+        })
+        .collect();
+}
+
+/// Key code that is reported in all slots when too many keys are pressed, see `RolloverPolicy`
+const KEY_ERROR_ROLLOVER: u8 = 0x01;
+
+/// What to do when more regular keys are pressed than fits in the six key slots
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RolloverPolicy {
+    /// Report ErrorRollOver in all slots, as the USB HID specification prescribes. Host then
+    /// keeps the previous state until the number of keys drops back to six.
+    ErrorRollOver,
+    /// Keep the six keys that are already in slots, and add new keys when slots get free
+    KeepOldest,
+    /// New key replaces the oldest key in slots. The replaced key is reported again when some
+    /// slot gets free.
+    ReplaceOldest,
+}
+
+/// Write pressed keys to 6 slots that are send over usb. Keys stay in their slots as long as they
+/// are pressed.
 /// Performance info: about 3 microseconds (negligible)
+/// # Arguments
+/// * `key_slots_prev`    Slots of the previous report
+/// * `slot_order`        Keys in slots in the order they were added, oldest first. This is
+///                       updated to match the returned slots.
+/// * `regular_keys`      Currently pressed regular keys
+/// * `regular_keys_prev` Pressed regular keys of the previous report, used to tell new presses
+/// * `remove_only`       Only release keys, do not add new ones
+/// * `policy`            What to do if all slots are full
 fn update_slots(
     key_slots_prev: &[Option<u8>; 6],
+    slot_order: &mut ShortVec<u8>,
     regular_keys: &ShortVec<KeyCode<u8>>,
+    regular_keys_prev: &ShortVec<KeyCode<u8>>,
     remove_only: bool,
+    policy: RolloverPolicy,
 ) -> [Option<u8>; 6] {
     // Copy
     let mut key_slots_new = *key_slots_prev;
@@ -143,6 +340,9 @@ fn update_slots(
     key_slots_new.iter_mut()
         .filter(|s| s.filter(|s| !regular_keys.iter().any(|k| k.into_inner() == *s)).is_some())
         .for_each(|s| *s = None);
+    *slot_order = slot_order.iter().copied()
+        .filter(|&k| key_slots_new.iter().contains(&Some(k)))
+        .collect();
 
     if remove_only {
         return key_slots_new;
@@ -156,16 +356,32 @@ fn update_slots(
             continue;
         }
         // add them to first free `None` spot
-        for slot in key_slots_new.iter_mut() {
-            if slot.is_none() {
+        if let Some(slot) = key_slots_new.iter_mut().find(|s| s.is_none()) {
+            *slot = Some(k);
+            slot_order.push(k).unwrap_or(());
+        } else if policy == RolloverPolicy::ReplaceOldest
+            && !regular_keys_prev.iter().any(|p| p.into_inner() == k)
+        {
+            // Only new presses replace keys, otherwise replaced keys would replace back
+            let oldest = slot_order.first().copied();
+            if let Some(slot) = key_slots_new.iter_mut().find(|s| s.is_some() && **s == oldest) {
+                // New key takes the slot of the oldest key, other keys stay in their slots
                 *slot = Some(k);
-                break;
+                *slot_order = slot_order.iter().skip(1).copied().collect();
+                slot_order.push(k).unwrap_or(());
             }
         }
     }
     return key_slots_new;
 }
 
+/// Whether more distinct regular keys are certainly pressed than fits in six slots
+fn is_rolled_over(regular_keys: &ShortVec<KeyCode<u8>>) -> bool {
+    let keys: ShortVec<u8> = regular_keys.iter().filter_map(|k| k.into_option()).collect();
+    let distinct = keys.iter().enumerate().filter(|&(i, k)| !keys[..i].contains(k)).count();
+    return distinct > 6;
+}
+
 fn set_modifier_keys(keyboard: &mut KBoard, modifier_slots: u16) {
     unsafe {
         keyboard.set_modifier(modifier_slots);
@@ -182,34 +398,49 @@ fn set_regular_keys(keyboard: &mut KBoard, key_slots: &[Option<u8>; 6]) {
     }
 }
 /// Send Fn and media keys (volmue up and down)
-/// Media keys does not have u8 key codes, so their presses must be emulated on higher level
+/// Media keys does not have u8 key codes, so their presses must be emulated on higher level.
+/// In typematic mode, media keys whose repeat is off are released immediately after press. Other
+/// media keys are held, and the host repeats them, i.e. their typematic delay and interval do
+/// not apply.
+/// Media key code is decided when the key is pressed, and `media_pressed` remembers the codes
+/// that are still pressed, so that changing host profile or typematic mode meanwhile does not
+/// leave media keys pressed.
 fn set_media_keys(
     keyboard: &mut KBoard,
     key_slots_fn: &[Option<u8>; 6],
     key_slots_fn_prev: &[Option<u8>; 6],
+    media_pressed: &mut ShortVec<(u8, u16)>,
+    typematic: &Typematic,
+    os_profiles: &OsProfiles,
     info: &ExtraKeyInfo,
 ) {
+    let no_repeat = |media_key: u32| {
+        typematic.enabled && typematic.repeat_of(media_key) == Repeat::Off
+    };
     let keys = key_slots_fn.iter().filter_map(|k| *k);
     let keys_old = key_slots_fn_prev.iter().filter_map(|k| *k);
-    for k_old in keys_old.clone() {
-        // If this key has disappeared from current list, prepare to release corresponding media key
-        if !keys.clone().contains(k_old) {
-            for &(regular_key, media_key) in info.media_key_bindings.iter() {
-                if regular_key == ((k_old as u32) | 0xF000) {
-                    unsafe {
-                        keyboard.release(media_key as u16);
-                    }
-                }
-            }
+    // Release media keys whose regular key has disappeared from current list
+    for &(_, media_key) in media_pressed.iter().filter(|&&(k, _)| !keys.clone().contains(k)) {
+        unsafe {
+            keyboard.release(media_key);
         }
     }
+    *media_pressed = media_pressed.iter().filter(|&&(k, _)| keys.clone().contains(k)).copied()
+        .collect();
     for k in keys {
         // If this key was not pressed on last time, prepare to press down corresponding media key
         if !keys_old.clone().contains(k) {
             for &(regular_key, media_key) in info.media_key_bindings.iter() {
+                let media_key = os_profiles.remap_media(media_key);
                 if regular_key == ((k as u32) | 0xF000) {
                     unsafe {
                         keyboard.press(media_key as u16);
+                        if no_repeat(media_key) {
+                            keyboard.release(media_key as u16);
+                        }
+                    }
+                    if !no_repeat(media_key) {
+                        media_pressed.push((k, media_key as u16)).unwrap_or(());
                     }
                 }
             }
@@ -217,16 +448,36 @@ fn set_media_keys(
     }
 }
 
-/// Pause so that keys are sent synchronously every `rescan_interval` milliseconds
-fn wait(rescan_interval: u32, prev_loop: &mut MillisTimer) {
-    let elapsed = prev_loop.elapsed();
-    let sleep_time = if rescan_interval > elapsed {
-        rescan_interval - elapsed
-    } else {
-        0
-    };
-    delay(sleep_time);
-    *prev_loop = MillisTimer::new();
+/// Busy-wait for given number of microseconds
+pub fn delay_us(us: u32) {
+    let start = unsafe { b::micros() };
+    while unsafe { b::micros() }.wrapping_sub(start) < us {}
+}
+
+/// Periodic timer with microsecond resolution. Used to run scanning and reporting at their own
+/// intervals.
+struct Ticker {
+    interval: u32,
+    next: u32,
+}
+
+impl Ticker {
+    fn new(interval: u32) -> Ticker {
+        Ticker { interval, next: unsafe { b::micros() } }
+    }
+
+    /// Return true if it is time for the next tick, and schedule the one after it.
+    fn is_due(&mut self, now: u32) -> bool {
+        if (now.wrapping_sub(self.next) as i32) < 0 {
+            return false;
+        }
+        self.next = self.next.wrapping_add(self.interval);
+        // If ticks have been missed (e.g. after sleeping), do not try to catch up with them
+        if (now.wrapping_sub(self.next) as i32) >= 0 {
+            self.next = now.wrapping_add(self.interval);
+        }
+        return true;
+    }
 }
 
 /// Blink the light twice to know we're alive
@@ -239,7 +490,7 @@ pub fn alive(led: &mut Pin) {
     delay(200)
 }
 
-#[no_mangle]
+#[cfg_attr(not(test), no_mangle)]
 pub extern "C" fn main() {
     let mut pinrow = PinRow::new_once();
     let mut led = pinrow.get_led();
@@ -251,78 +502,594 @@ pub extern "C" fn main() {
     
     // To generate keyboard matrix, uncomment 'ask_key_codes_and_print_them',
     // and copy-paste generated source into 'get_stored_key_codes'
-    //let mut mat = custom_key_codes::ask_key_codes_and_print_them(&mut pinrow);
-    let mut mat = custom_key_codes::get_stored_key_codes(&mut pinrow);
+    //let mat = custom_key_codes::ask_key_codes_and_print_them(&mut pinrow);
+    let mat = custom_key_codes::get_stored_key_codes(&mut pinrow);
+    // Keyboard may consist of multiple key matrices, e.g. separate numpad. Add them all here.
+    let mut mats = KeyMatrices::new(custom_key_codes::extra_information_about_key_codes());
+    mats.add(mat);
+    // Measure how fast GPIO pins settle, so that scanning does not need to sleep needlessly long
+    mats.calibrate_settle_times();
     
     // Key presses from previous cycle
     let mut key_slots_prev: [Option<u8>; 6] = [None; 6];        // Normal keys
     let mut key_slots_fn_prev: [Option<u8>; 6] = [None; 6];     // Media keys (fn combinations)
+    let mut slot_order: ShortVec<u8> = Vec::new();              // Keys in slots, oldest first
+    let mut slot_order_fn: ShortVec<u8> = Vec::new();
     let mut modifier_slots_prev: u16 = 0;                       // Ctrl, Shift, Alt, AltGr
     let mut fn_key_prev: bool = false;                          // Fn
+    let mut regular_keys_prev: ShortVec<KeyCode<u8>> = Vec::new(); // Pressed regular keys
+    let mut report_slots_prev: [Option<u8>; 6] = [None; 6];    // Normal keys as sent
+    let mut media_pressed: ShortVec<(u8, u16)> = Vec::new();   // Media keys that are held
+
+    // Scanning, debouncing and reporting are decoupled: Scanning produces timestamped key events
+    // to a queue, and reporting drains it with USB polling rate. With calibrated GPIO pin
+    // settlement one scan takes well below a millisecond, so scan rate can be 1 kHz. Between ticks
+    // the controller sleeps until the millisecond timer interrupt, so intervals should be whole
+    // milliseconds.
+    let scan_interval = 1000; // microseconds
+    let report_interval = 1000; // microseconds, USB polling interval of keyboard
+    let debounce_time = 30_000; // microseconds
+    let mut scan_ticker = Ticker::new(scan_interval);
+    let mut report_ticker = Ticker::new(report_interval);
+    let mut debouncer = Debouncer::new(debounce_time);
+    debouncer.load_debounce_times();
+    // Keys that bounce on at least 10 % of presses get 10 ms longer debounce time, at most 80 ms.
+    // Tuned keys are kept over power off in EEPROM. They are printed with serial command 'd', and
+    // reset with 'D'.
+    let mut chatter_tuner = ChatterTuner::new(20, 10, 10_000, 80_000);
+    let mut events = EventQueue::new();
+    // Currently pressed keys, as seen by the reporting stage
+    let mut held: ShortVec<(KeyPos, KeyCode<u32>)> = Vec::new();
+    // Press times of currently pressed keys (in microseconds)
+    let mut press_times: ShortVec<(KeyPos, u32)> = Vec::new();
+    // How to handle ambiguous presses, where some key may be ghost press
+    let uncertain_policy = UncertainPolicy::HoldPrevious;
+    // What to do when more than six regular keys are pressed
+    let rollover_policy = RolloverPolicy::KeepOldest;
+
+    // Latency measurement mode. It is toggled with serial command 'm', and statistics are
+    // printed with 'l'.
+    let mut measure_latency = false;
+    let mut latency_meter = LatencyMeter::new();
+    // Scan and debounce times of events that are waiting to be sent
+    let mut unsent_times: ShortVec<(u32, u32)> = Vec::new();
+
+    // Key press statistics. They are printed with serial command 's', and saved to EEPROM
+    // every 10 minutes if changed.
+    let mut stats = KeyStats::load();
+    let mut stats_save_ticker = Ticker::new(600_000_000);
+    // Keys that did not fit in USB report last time
+    let mut dropped_prev: ShortVec<KeyPos> = Vec::new();
+
+    // Keys that are pressed longer than this are considered stuck, and they are ignored until
+    // released. Regular keys, like arrows or game keys, may be held for long, so they are not
+    // masked unless timeout is given, e.g. `Some(600_000_000)`. Timeouts must stay below 71
+    // minutes, when time stamps wrap around. Stuck keys are printed with 'k'.
+    let mut stuck_keys = StuckKeys::new(None, 300_000_000); // microseconds
+
+    // Chords of keys that emit different key, see `custom_key_codes::COMBOS`
+    let combo_window = 50_000; // microseconds
+    let mut combos = custom_key_codes::get_combos(&mats, combo_window);
+    // Keys that do different things when tapped multiple times or held, see
+    // `custom_key_codes::TAP_DANCES`
+    let tapping_term = 200_000; // microseconds
+    let mut tap_dances = custom_key_codes::get_tap_dances(&mats, tapping_term);
+
+    // Sticky modifiers and Fn layer, which apply to the next key press, see `one_shot`
+    let one_shot_timeout = 3_000_000; // microseconds
+    let mut one_shot = OneShot::new(one_shot_timeout);
+
+    // Leader key sequences, see `custom_key_codes::LEADER_SEQUENCES`
+    let leader_timeout = 1_000_000; // microseconds
+    let mut leader = custom_key_codes::get_leader(leader_timeout);
+
+    // Shifts letters until the end of word, see `caps_word`
+    let caps_word_timeout = 5_000_000; // microseconds
+    let mut caps_word = CapsWord::new(caps_word_timeout);
+
+    // Long press sends shifted key, see `auto_shift`. Hold time thresholds for letters, numbers
+    // and symbols in microseconds, e.g. `Some(200_000)`, or None to disable.
+    let mut auto_shift = AutoShift::new(None, None, None);
 
-    // Key presses from previous 3 cycles. Used only for debouncing, i.e. fixing rare misbehaviour
-    let mut scan1: Option<ShortVec<KeyCode<u32>>> = None;
-    let mut scan2: Option<ShortVec<KeyCode<u32>>> = None;
-    let mut scan3: Option<ShortVec<KeyCode<u32>>> = None;
+    // Firmware-side key repeat, for hosts whose repeat settings can not be changed. It is
+    // toggled with serial command 't'. Per key settings are in `custom_key_codes::KEY_REPEATS`.
+    let typematic_enabled = false;
+    let default_repeat = Repeat::On { delay: 500_000, interval: 33_333 }; // microseconds
+    let mut typematic = custom_key_codes::get_typematic(typematic_enabled, default_repeat);
 
-    // Note that due to GPIO pin settlement (sleep 1ms) best possible scan rate is about 10ms.
-    let rescan_interval = 10; // milliseconds
-    let mut prev_loop = MillisTimer::new();
+    // Modifier + key combinations that send different key, see
+    // `custom_key_codes::KEY_OVERRIDES`
+    let mut key_overrides = custom_key_codes::get_key_overrides();
+
+    // Host OS profile remaps keys for Linux, Windows or Mac, see
+    // `custom_key_codes::OS_PROFILES`. Selected profile is kept over power off in EEPROM.
+    let mut os_profiles = custom_key_codes::get_os_profiles();
+
+    // Letter layout (Qwerty, Dvorak or Colemak) done in firmware, see
+    // `custom_key_codes::LAYOUTS`. Selected layout is kept over power off in EEPROM. Keys pressed
+    // with these modifiers stay on QWERTY positions, so that Ctrl-C is where C was. Use 0 to
+    // remap shortcuts too.
+    let shortcut_modifiers = b::MODIFIERKEY_LEFT_CTRL | b::MODIFIERKEY_RIGHT_CTRL
+        | b::MODIFIERKEY_LEFT_GUI | b::MODIFIERKEY_RIGHT_GUI;
+    let mut layouts = custom_key_codes::get_layouts(shortcut_modifiers);
+
+    // Macro that is playing, see `custom_key_codes::MACROS`. After macro, the keys that are
+    // physically pressed are reported again.
+    let mut macro_player = MacroPlayer::new();
+    let mut restore_report = false;
+    // Macro that is recorded on the fly. It is kept over power off in EEPROM, and printed with
+    // serial command 'r'.
+    let mut dynamic_macro = DynamicMacro::new(true);
+
+    // Go to low-power sleep if nothing is pressed for this long
+    let idle_timeout = 60_000; // milliseconds
+    let mut idle_state = IdleState::new(idle_timeout);
     
     let mut keyboard = unsafe { b::Keyboard };
     println!("Entering main loop");
     loop {
-        wait(rescan_interval, &mut prev_loop);
-        let scan0 = mat.scan_key_press();
+        let now = unsafe { b::micros() };
+        let scan_due = scan_ticker.is_due(now);
+        let report_due = report_ticker.is_due(now);
+        if !scan_due && !report_due {
+            idle::wait_for_interrupt();  // Timer wakes up every millisecond
+            continue;
+        }
+
+        if scan_due {
+            let scan = mats.scan_key_press();
+            // Fix hardware glitch where voltage bounces back after releasing the key
+            let bounced = debouncer.debounce(scan, now, &mut events);
+            for &pos in bounced.iter() {
+                stats.record_chatter(pos);
+                chatter_tuner.record_bounce(pos);
+            }
+        }
+        if !report_due {
+            continue;
+        }
+
+        match console::read_command() {
+            Some(b'm') => {
+                measure_latency = !measure_latency;
+                println!("Latency measurement {}", if measure_latency { "on" } else { "off" });
+            }
+            Some(b'l') => latency_meter.print(),
+            Some(b'L') => latency_meter = LatencyMeter::new(),
+            Some(b's') => custom_key_codes::print_key_statistics(&stats, &mats),
+            Some(b'S') => {
+                stats.reset();
+                stats.save();
+                println!("Key statistics reset");
+            }
+            Some(b'd') => {
+                println!("Default debounce time: {} us", debouncer.debounce_time);
+                for (pos, time) in debouncer.key_debounce_times.iter() {
+                    println!("    {:?}: {} us", pos, time);
+                }
+            }
+            Some(b'D') => {
+                debouncer.reset_debounce_times();
+                debouncer.save_debounce_times();
+                chatter_tuner.reset();
+                println!("Debounce times reset");
+            }
+            Some(b'k') => stuck_keys.print(),
+            Some(b'r') => dynamic_macro.print(),
+            Some(b't') => {
+                typematic.enabled = !typematic.enabled;
+                println!("Typematic mode {}", if typematic.enabled { "on" } else { "off" });
+            }
+            Some(_) => console::print_help(),
+            None => {}
+        }
 
-        // Fix hardware glitch where voltage bounces back after releasing the key
-        let scan = process_keys::debounce(scan0, &scan1, &scan2, &scan3);
-        scan3 = scan2;
-        scan2 = scan1;
-        scan1 = scan.clone();
+        if stats_save_ticker.is_due(now) && stats.unsaved {
+            stats.save();
+        }
+
+        // Physical key events
+        let mut combined = EventVec::new();
+        while let Some(mut event) = events.dequeue() {
+            layouts.remap(&mut event, &mats.info);
+            let was_held = press_times.iter().any(|&(pos, _)| pos == event.pos);
+            events::update_press_times(&mut press_times, &event);
+            if !stuck_keys.filter_event(&event) {
+                continue;
+            }
+            stats.record_event(&event, was_held);
+            if event.pressed && !was_held {
+                let old_time = debouncer.debounce_time_of(event.pos);
+                if let Some(new_time) = chatter_tuner.record_press(event.pos, old_time) {
+                    match debouncer.set_debounce_time(event.pos, new_time) {
+                        Ok(()) => {
+                            debouncer.save_debounce_times();
+                            println!("Key {:?} chatters, debounce time raised to {} us",
+                                     event.pos, new_time);
+                        }
+                        Err(()) => println!("Key {:?} chatters, but too many keys are tuned",
+                                            event.pos),
+                    }
+                }
+            }
+            combos.process(event, &mut combined);
+        }
+        combos.update(now, &mut combined);
+        let mut danced = EventVec::new();
+        tap_dances.update(now, &mut danced);
+        combined.into_iter().for_each(|event| tap_dances.process(event, &mut danced));
+        let mut led_events = EventVec::new();
+        leader.update(now, &mut led_events);
+        danced.into_iter().for_each(|event| leader.process(event, &mats.info, &mut led_events));
+        let mut worded = EventVec::new();
+        caps_word.update(now);
+        led_events.into_iter().for_each(|event| caps_word.process(event, &mats.info, &mut worded));
+        let mut shifted = EventVec::new();
+        auto_shift.update(now, &mut shifted);
+        worded.into_iter().for_each(|event| auto_shift.process(event, &mats.info, &mut shifted));
+
+        // Key events after combos, tap dances, leader sequences, Caps Word and auto-shift
+        let mut changed = leader.take_layer_change();
+        for event in shifted.iter() {
+            let was_held = held.iter().any(|&(pos, _)| pos == event.pos);
+            if event.pressed && !was_held {
+                match event.code.into_option() {
+                    Some(dynamic_macro::RECORD_KEY) => dynamic_macro.toggle_recording(),
+                    Some(dynamic_macro::PLAY_KEY) if !dynamic_macro.is_recording() => {
+                        macro_player.start_recorded(dynamic_macro.steps());
+                    }
+                    Some(c) if c.to_le_bytes()[1] == os_profile::PROFILE_KEY_MASK => {
+                        os_profiles.profile.select(c);
+                    }
+                    Some(c) if c.to_le_bytes()[1] == layouts::LAYOUT_KEY_MASK => {
+                        // Keys that are already pressed keep their codes until they are released
+                        layouts.layout.select(c);
+                    }
+                    Some(c) => {
+                        if let Some(steps) = macros::macro_of(c, &mats.info) {
+                            macro_player.start(steps);
+                        }
+                    }
+                    None => {}
+                }
+            }
+            one_shot.process(event, &mats.info);
+            events::apply_event(&mut held, event);
+            changed = true;
+            if measure_latency {
+                unsent_times.push((event.time, event.debounced)).unwrap_or(());
+            }
+        }
 
-        // Proceed to send key states only if something is pressed
-        if scan.is_none()
+        if stuck_keys.mask_stuck_keys(&mut held, &press_times, now, &mats.info) {
+            changed = true;
+        }
+        if one_shot.update(now) {
+            changed = true;
+        }
+        if let Some(code) = leader.take_system_key() {
+            unsafe {
+                keyboard.press(code as u16);
+                keyboard.release(code as u16);
+            }
+        }
+
+        let nothing_pressed = held.is_empty()
             && key_slots_prev.iter().all(|s| s.is_none())
             && key_slots_fn_prev.iter().all(|s| s.is_none())
-            && modifier_slots_prev == 0
-        {
+            && modifier_slots_prev == 0;
+
+        if idle_state.update(!nothing_pressed, report_interval / 1000) == PowerMode::Sleeping {
+            idle::sleep_until_key_press(&mut mats);
+            idle_state.wake_up();
+            continue;
+        }
+
+        if macro_player.is_playing() {
+            if let Some(report) = macro_player.next_report(now) {
+                set_modifier_keys(&mut keyboard, report.modifiers);
+                set_regular_keys(&mut keyboard, &report.keys);
+                unsafe {
+                    keyboard.send_now();
+                }
+            }
+            restore_report = true;
+            unsent_times.clear();
             continue;
         }
-        let (regular_keys, modifier_keys, fn_key) = categorize_key_presses(
+
+        // Proceed to send key states only if something has changed
+        if !changed && !restore_report && !typematic.is_active() {
+            continue;
+        }
+        let scan = if held.is_empty() { None } else { Some(held.clone()) };
+        let (mut regular_keys, modifier_keys, fn_key) = categorize_key_presses(
             &scan,
+            &press_times,
             &key_slots_prev,
             modifier_slots_prev,
             fn_key_prev,
-            &mat.info,
+            uncertain_policy,
+            &mats.info,
         );
 
-        let modifier_slots = modifier_keys.iter().fold(0, |acc, k| k.into_inner() | acc);
-        let key_slots = update_slots(&key_slots_prev, &regular_keys, fn_key);
-        let key_slots_fn = update_slots(&key_slots_fn_prev, &regular_keys, !fn_key);
+        let fn_key = fn_key || one_shot.fn_layer() || leader.fn_layer();
+        let modifier_slots = modifier_keys.iter()
+            .fold(one_shot.modifiers(), |acc, k| k.into_inner() | acc);
+        let scanned_keys = regular_keys.clone();
+        let modifier_slots = os_profiles.remap(modifier_slots, &mut regular_keys, &mats.info);
+        let modifier_slots =
+            key_overrides.apply(modifier_slots, &mut regular_keys, fn_key, &mats.info);
+        let key_slots = update_slots(
+            &key_slots_prev,
+            &mut slot_order,
+            &regular_keys,
+            &regular_keys_prev,
+            fn_key,
+            rollover_policy,
+        );
+        let key_slots_fn = update_slots(
+            &key_slots_fn_prev,
+            &mut slot_order_fn,
+            &regular_keys,
+            &regular_keys_prev,
+            !fn_key,
+            rollover_policy,
+        );
+        let rollover = rollover_policy == RolloverPolicy::ErrorRollOver
+            && !fn_key
+            && is_rolled_over(&regular_keys);
+        // Slots as they are sent, which differ from `key_slots` on rollover and in typematic mode
+        let report_slots = if rollover {
+            [Some(KEY_ERROR_ROLLOVER); 6]
+        } else {
+            typematic.apply(&key_slots, now)
+        };
+
+        // Count presses that did not fit in USB report
+        let slots = if fn_key { &key_slots_fn } else { &key_slots };
+        let n_matrices = mats.matrices.len();
+        let dropped =
+            dropped_keys(&held, n_matrices, &scanned_keys, &regular_keys, slots, &mats.info);
+        for &pos in dropped.iter().filter(|pos| !dropped_prev.contains(pos)) {
+            stats.record_dropped(pos);
+        }
+        dropped_prev = dropped;
+        regular_keys_prev = regular_keys;
 
 //         println!(
 //             "mod: {:016b}{:<8}keys: {:?}{:<16}key_slots_fn: {:?}",
 //             modifier_slots, "\n", key_slots, "\n", key_slots_fn
 //         );
 
-        // Proceed to send key states only if they are changed. (A tiny performance optimization)
-        if modifier_slots != modifier_slots_prev {
+        // Proceed to send key states only if they are changed. This greatly reduces lag by not
+        // flooding USB with unnecessary packets.
+        let send = restore_report
+            || modifier_slots != modifier_slots_prev
+            || report_slots != report_slots_prev
+            || key_slots_fn != key_slots_fn_prev;
+        if dynamic_macro.is_recording() {
+            dynamic_macro.record_report(
+                modifier_slots_prev, modifier_slots, &key_slots_prev, &key_slots
+            );
+        }
+        if modifier_slots != modifier_slots_prev || restore_report {
             set_modifier_keys(&mut keyboard, modifier_slots);
             modifier_slots_prev = modifier_slots;
         }
-        if key_slots != key_slots_prev {
-            set_regular_keys(&mut keyboard, &key_slots);
-            key_slots_prev = key_slots;
+        if report_slots != report_slots_prev || restore_report {
+            set_regular_keys(&mut keyboard, &report_slots);
+            report_slots_prev = report_slots;
         }
+        key_slots_prev = key_slots;
         if key_slots_fn != key_slots_fn_prev {
-            set_media_keys(&mut keyboard, &key_slots_fn, &key_slots_fn_prev, &mat.info);
+            set_media_keys(
+                &mut keyboard,
+                &key_slots_fn,
+                &key_slots_fn_prev,
+                &mut media_pressed,
+                &typematic,
+                &os_profiles,
+                &mats.info,
+            );
             key_slots_fn_prev = key_slots_fn;
         }
         fn_key_prev = fn_key;
+        restore_report = false;
 
-        unsafe {
-            keyboard.send_now();
+        if send {
+            unsafe {
+                keyboard.send_now();
+            }
+            if measure_latency {
+                let sent = unsafe { b::micros() };
+                for &(scanned, debounced) in unsent_times.iter() {
+                    latency_meter.record(scanned, debounced, sent);
+                }
+            }
+        }
+        // Events that did not change the report are not measured
+        unsent_times.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Positions and press times of uncertain keys
+    fn uncertain(keys: &[(usize, usize, u32)]) -> ShortVec<(KeyPos, u32)> {
+        return keys.iter().map(|&(row, col, t)| (KeyPos::new(0, row, col), t)).collect();
+    }
+
+    /// Which of the keys are registered with given policy
+    fn registered(policy: UncertainPolicy, keys: &[(usize, usize, u32)]) -> [bool; 4] {
+        let uncertain = uncertain(keys);
+        let mut result = [false; 4];
+        for (i, &(pos, _)) in uncertain.iter().enumerate() {
+            result[i] = resolve_uncertain(policy, pos, false, &uncertain);
         }
+        return result;
+    }
+
+    // Rectangle where (0, 0) and (0, 1) are pressed first, and (1, 1) completes the rectangle so
+    // that ghost appears in (1, 0).
+    const ROW_FIRST: [(usize, usize, u32); 4] =
+        [(0, 0, 100), (0, 1, 200), (1, 0, 300), (1, 1, 300)];
+    // Same keys, but (0, 1) is pressed first, so (1, 0) is the real one
+    const ROW_FIRST_REVERSED: [(usize, usize, u32); 4] =
+        [(0, 0, 200), (0, 1, 100), (1, 0, 300), (1, 1, 300)];
+    // Diagonal corners are pressed first, so the newest press can not be told apart
+    const DIAGONAL_FIRST: [(usize, usize, u32); 4] =
+        [(0, 0, 100), (1, 1, 200), (0, 1, 300), (1, 0, 300)];
+
+    #[test]
+    fn timing_based_picks_real_corner() {
+        let policy = UncertainPolicy::TimingBased;
+        assert_eq!(registered(policy, &ROW_FIRST), [true, true, false, true]);
+        assert_eq!(registered(policy, &ROW_FIRST_REVERSED), [true, true, true, false]);
+        assert_eq!(registered(policy, &DIAGONAL_FIRST), [true, true, false, false]);
+    }
+
+    #[test]
+    fn prefer_most_recent_registers_newest_real_press() {
+        let policy = UncertainPolicy::PreferMostRecent;
+        assert_eq!(registered(policy, &ROW_FIRST), [false, false, false, true]);
+        assert_eq!(registered(policy, &ROW_FIRST_REVERSED), [false, false, true, false]);
+        assert_eq!(registered(policy, &DIAGONAL_FIRST), [false, false, false, false]);
+    }
+
+    #[test]
+    fn single_newest_press_is_registered() {
+        // E.g. the ghost corner was released, and the real corner is still uncertain
+        let keys = [(0, 0, 100), (0, 1, 200), (1, 1, 300)];
+        assert_eq!(registered(UncertainPolicy::TimingBased, &keys), [true, true, true, false]);
+        assert_eq!(registered(UncertainPolicy::PreferMostRecent, &keys),
+                   [false, false, true, false]);
+    }
+
+    #[test]
+    fn press_times_wrap_around() {
+        let keys = [(0, 0, u32::MAX - 200), (0, 1, u32::MAX - 100), (1, 0, 50), (1, 1, 50)];
+        assert_eq!(registered(UncertainPolicy::TimingBased, &keys), [true, true, false, true]);
+        assert_eq!(registered(UncertainPolicy::PreferMostRecent, &keys),
+                   [false, false, false, true]);
+    }
+
+    #[test]
+    fn hold_previous_and_drop_all() {
+        let uncertain = uncertain(&ROW_FIRST);
+        let (pos, _) = uncertain[3];
+        assert!(resolve_uncertain(UncertainPolicy::HoldPrevious, pos, true, &uncertain));
+        assert!(!resolve_uncertain(UncertainPolicy::HoldPrevious, pos, false, &uncertain));
+        assert!(!resolve_uncertain(UncertainPolicy::DropAll, pos, true, &uncertain));
+        // Press time is not known, e.g. the key is masked as stuck
+        let unknown = KeyPos::new(0, 5, 5);
+        assert!(resolve_uncertain(UncertainPolicy::HoldPrevious, unknown, true, &uncertain));
+        assert!(!resolve_uncertain(UncertainPolicy::TimingBased, unknown, true, &uncertain));
+    }
+
+    /// Certainly pressed regular keys
+    fn keys(codes: &[u8]) -> ShortVec<KeyCode<u8>> {
+        return codes.iter().map(|&c| KeyCode::Certain(c)).collect();
+    }
+
+    /// Update slots with given pressed keys, and return the new slots
+    fn press(
+        slots: &mut [Option<u8>; 6],
+        order: &mut ShortVec<u8>,
+        prev: &mut ShortVec<KeyCode<u8>>,
+        codes: &[u8],
+        policy: RolloverPolicy,
+    ) -> [Option<u8>; 6] {
+        let pressed = keys(codes);
+        *slots = update_slots(slots, order, &pressed, prev, false, policy);
+        *prev = pressed;
+        return *slots;
+    }
+
+    #[test]
+    fn released_key_frees_slot_for_waiting_key() {
+        let (mut slots, mut order, mut prev) = ([None; 6], Vec::new(), Vec::new());
+        let policy = RolloverPolicy::KeepOldest;
+        press(&mut slots, &mut order, &mut prev, &[4, 5, 6, 7, 8, 9], policy);
+        let full = [Some(4), Some(5), Some(6), Some(7), Some(8), Some(9)];
+        assert_eq!(press(&mut slots, &mut order, &mut prev, &[4, 5, 6, 7, 8, 9, 10], policy), full);
+        // Released key is replaced by the waiting key, and the others stay in their slots
+        assert_eq!(press(&mut slots, &mut order, &mut prev, &[4, 5, 7, 8, 9, 10], policy),
+                   [Some(4), Some(5), Some(10), Some(7), Some(8), Some(9)]);
+        // Releasing all but the last key leaves it reported
+        assert_eq!(press(&mut slots, &mut order, &mut prev, &[10], policy),
+                   [None, None, Some(10), None, None, None]);
+        assert_eq!(press(&mut slots, &mut order, &mut prev, &[], policy), [None; 6]);
+    }
+
+    #[test]
+    fn replace_oldest_keeps_held_keys_in_their_slots() {
+        let (mut slots, mut order, mut prev) = ([None; 6], Vec::new(), Vec::new());
+        let policy = RolloverPolicy::ReplaceOldest;
+        press(&mut slots, &mut order, &mut prev, &[4, 5, 6, 7, 8, 9], policy);
+        assert_eq!(press(&mut slots, &mut order, &mut prev, &[4, 5, 6, 7, 8, 9, 10], policy),
+                   [Some(10), Some(5), Some(6), Some(7), Some(8), Some(9)]);
+        assert_eq!(press(&mut slots, &mut order, &mut prev, &[4, 5, 6, 7, 8, 9, 10, 11], policy),
+                   [Some(10), Some(11), Some(6), Some(7), Some(8), Some(9)]);
+        // Keys that were replaced do not replace back while they are held
+        assert_eq!(press(&mut slots, &mut order, &mut prev, &[4, 5, 6, 7, 8, 9, 10, 11], policy),
+                   [Some(10), Some(11), Some(6), Some(7), Some(8), Some(9)]);
+        // Replaced key is reported again when a slot gets free
+        assert_eq!(press(&mut slots, &mut order, &mut prev, &[4, 5, 6, 7, 8, 10, 11], policy),
+                   [Some(10), Some(11), Some(6), Some(7), Some(8), Some(4)]);
+        assert_eq!(&order[..], &[6, 7, 8, 10, 11, 4]);
+    }
+
+    #[test]
+    fn uncertain_keys_are_kept_but_not_added() {
+        let (mut slots, mut order) = ([None; 6], Vec::new());
+        let policy = RolloverPolicy::KeepOldest;
+        slots = update_slots(&slots, &mut order, &keys(&[4]), &Vec::new(), false, policy);
+        let mut pressed = keys(&[]);
+        pressed.push(KeyCode::Uncertain(4)).unwrap();
+        pressed.push(KeyCode::Uncertain(5)).unwrap();
+        slots = update_slots(&slots, &mut order, &pressed, &keys(&[4]), false, policy);
+        assert_eq!(slots, [Some(4), None, None, None, None, None]);
+    }
+
+    #[test]
+    fn remove_only_releases_but_does_not_add() {
+        let (mut slots, mut order) = ([None; 6], Vec::new());
+        let policy = RolloverPolicy::KeepOldest;
+        slots = update_slots(&slots, &mut order, &keys(&[4, 5]), &Vec::new(), false, policy);
+        slots = update_slots(&slots, &mut order, &keys(&[5, 6]), &keys(&[4, 5]), true, policy);
+        assert_eq!(slots, [None, Some(5), None, None, None, None]);
+        assert_eq!(&order[..], &[5]);
+    }
+
+    #[test]
+    fn rollover_counts_distinct_certain_keys() {
+        assert!(!is_rolled_over(&keys(&[4, 5, 6, 7, 8, 9])));
+        assert!(!is_rolled_over(&keys(&[4, 5, 6, 7, 8, 9, 9])));
+        assert!(is_rolled_over(&keys(&[4, 5, 6, 7, 8, 9, 10])));
+    }
+
+    #[test]
+    fn dropped_keys_follow_replaced_codes() {
+        let info = custom_key_codes::extra_information_about_key_codes();
+        let mut held: ShortVec<(KeyPos, KeyCode<u32>)> = (0..7)
+            .map(|i| (KeyPos::new(0, 0, i), KeyCode::Certain(0xF004 + i as u32)))
+            .collect();
+        let scanned = keys(&[4, 5, 6, 7, 8, 9, 10]);
+        // Key 4 is replaced by 0x4C, e.g. by key override
+        let replaced = keys(&[0x4C, 5, 6, 7, 8, 9, 10]);
+        let slots = [Some(0x4C), Some(5), Some(6), Some(7), Some(8), Some(9)];
+        assert_eq!(&dropped_keys(&held, 1, &scanned, &replaced, &slots, &info)[..],
+                   &[KeyPos::new(0, 0, 6)]);
+        // Free slot does not hide keys that did not fit
+        let slots = [Some(0x4C), Some(5), Some(6), Some(7), Some(8), None];
+        assert_eq!(&dropped_keys(&held, 1, &scanned, &replaced, &slots, &info)[..],
+                   &[KeyPos::new(0, 0, 5), KeyPos::new(0, 0, 6)]);
+        // Key of virtual matrix, e.g. combo, is not a physical key
+        held[6].0 = KeyPos::new(events::COMBO_MATRIX, 0, 0);
+        assert_eq!(&dropped_keys(&held, 1, &scanned, &replaced, &slots, &info)[..],
+                   &[KeyPos::new(0, 0, 5)]);
     }
 }
@@ -0,0 +1,217 @@
+//! One-shot (sticky) modifiers and Fn layer. When one-shot key is tapped, it stays active for
+//! the next non-modifier key press, so that key combinations can be typed one key at a time.
+//! Double tap locks the key until it is tapped again. If one-shot key is held, it acts as normal
+//! modifier. Tapped one-shot key is forgotten after `timeout`.
+//!
+//! One-shot keys are defined in `custom_key_codes.rs` with codes from `one_shot`, for example
+//! `one_shot(b::MODIFIERKEY_LEFT_SHIFT)`, or `ONE_SHOT_FN` for the Fn layer.
+
+use heapless::Vec; // fixed capacity `std::Vec`
+use typenum::U8 as OneShotCap; // Maximum number of one-shot keys that are active at once
+
+use crate::events::KeyEvent;
+use crate::process_keys::{ExtraKeyInfo, KeyPos};
+
+/// Byte mask of one-shot keys, i.e. the second byte of their key code
+pub const ONE_SHOT_MASK: u8 = 0xEA;
+/// One-shot Fn layer
+pub const ONE_SHOT_FN: u32 = 0xEA00;
+
+/// Key code of one-shot version of modifier key
+#[allow(dead_code)]
+pub const fn one_shot(modifier: u32) -> u32 {
+    return 0xEA00 | (modifier & 0xFF);
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum State {
+    /// One-shot key is held. If some other key has been pressed meanwhile, it is `used`.
+    Held { used: bool },
+    /// One-shot key was tapped, and it is waiting for the next key
+    Armed { since: u32 },
+    /// Key at `pos` was pressed when one-shot key was armed. It stays active until that key is
+    /// released.
+    Consumed { pos: KeyPos },
+    /// One-shot key was double tapped
+    Locked,
+}
+
+/// States of one-shot keys
+#[derive(Debug)]
+pub struct OneShot {
+    /// Tapped one-shot key is forgotten after this time (in microseconds)
+    pub timeout: u32,
+    /// Key code and state of active one-shot keys
+    keys: Vec<(u32, State), OneShotCap>,
+}
+
+impl OneShot {
+    pub fn new(timeout: u32) -> OneShot {
+        return OneShot { timeout, keys: Vec::new() };
+    }
+
+    /// Update states of one-shot keys according to event
+    pub fn process(&mut self, event: &KeyEvent, info: &ExtraKeyInfo) {
+        let code = event.code.into_inner();
+        if code.to_le_bytes()[1] == ONE_SHOT_MASK {
+            self.process_one_shot_key(code, event);
+            return;
+        }
+        // Other modifiers do not consume one-shot keys
+        let is_modifier = info.is_modifier(code);
+        if event.pressed && !is_modifier {
+            for (_, state) in self.keys.iter_mut() {
+                match *state {
+                    State::Held { .. } => *state = State::Held { used: true },
+                    State::Armed { .. } => *state = State::Consumed { pos: event.pos },
+                    _ => {}
+                }
+            }
+        } else if !event.pressed {
+            self.remove(|state| state == State::Consumed { pos: event.pos });
+        }
+    }
+
+    fn process_one_shot_key(&mut self, code: u32, event: &KeyEvent) {
+        let idx = self.keys.iter().position(|&(c, _)| c == code);
+        let state = idx.map(|i| self.keys[i].1);
+        let new_state = match (state, event.pressed) {
+            (None, true) => Some(State::Held { used: false }),
+            (Some(State::Consumed { .. }), true) => Some(State::Held { used: false }),
+            (Some(State::Armed { .. }), true) => Some(State::Locked),
+            // Tap unlocks the key
+            (Some(State::Locked), true) => Some(State::Held { used: true }),
+            (Some(State::Held { used: false }), false) => Some(State::Armed { since: event.time }),
+            (Some(State::Held { used: true }), false) => None,
+            (state, _) => state,
+        };
+        match (idx, new_state) {
+            (Some(i), Some(s)) => self.keys[i].1 = s,
+            (Some(i), None) => { self.keys.swap_remove(i); }
+            (None, Some(s)) => self.keys.push((code, s)).unwrap_or(()),
+            (None, None) => {}
+        }
+    }
+
+    /// Forget armed keys whose time is up. Returns true if something changed.
+    /// # Arguments
+    /// * `now` Current time (in microseconds)
+    pub fn update(&mut self, now: u32) -> bool {
+        let timeout = self.timeout;
+        return self.remove(|state| match state {
+            State::Armed { since } => now.wrapping_sub(since) >= timeout,
+            _ => false,
+        });
+    }
+
+    fn remove<F: Fn(State) -> bool>(&mut self, f: F) -> bool {
+        let len = self.keys.len();
+        let mut i = 0;
+        while i < self.keys.len() {
+            if f(self.keys[i].1) {
+                self.keys.swap_remove(i);
+            } else {
+                i += 1;
+            }
+        }
+        return self.keys.len() != len;
+    }
+
+    /// Active one-shot modifiers in the same form as `modifier_slots` in `main`. Armed modifiers
+    /// are not included, because they are sent only together with the key that consumes them.
+    pub fn modifiers(&self) -> u16 {
+        return self.keys.iter()
+            .filter(|&&(code, state)| code != ONE_SHOT_FN && !matches!(state, State::Armed { .. }))
+            .fold(0, |acc, &(code, _)| acc | 0xE000 | (code as u16 & 0xFF));
+    }
+
+    /// Whether one-shot Fn layer is active
+    pub fn fn_layer(&self) -> bool {
+        return self.keys.iter().any(|&(code, _)| code == ONE_SHOT_FN);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::testing::key_event;
+    use teensy3::bindings as b;
+
+    const TIMEOUT: u32 = 3_000_000;
+    const SHIFT: u32 = b::MODIFIERKEY_LEFT_SHIFT;
+    const ONE_SHOT_SHIFT: u32 = one_shot(SHIFT);
+
+    /// Process press and release of key, and return the modifiers that are active in between
+    /// and after
+    fn tap(one_shot: &mut OneShot, code: u32, time: u32) -> (u16, u16) {
+        let info = crate::custom_key_codes::extra_information_about_key_codes();
+        one_shot.process(&key_event(code, true, time), &info);
+        let during = one_shot.modifiers();
+        one_shot.process(&key_event(code, false, time + 500), &info);
+        return (during, one_shot.modifiers());
+    }
+
+    #[test]
+    fn tap_applies_to_next_key_only() {
+        let mut one_shot = OneShot::new(TIMEOUT);
+        // Tapped modifier is not sent on its own...
+        assert_eq!(tap(&mut one_shot, ONE_SHOT_SHIFT, 0).1, 0);
+        // ...but together with the next key, until that key is released
+        assert_eq!(tap(&mut one_shot, b::KEY_A, 1000), (SHIFT as u16, 0));
+        assert_eq!(tap(&mut one_shot, b::KEY_B, 2000), (0, 0));
+    }
+
+    #[test]
+    fn modifiers_do_not_consume_one_shot_key() {
+        let mut one_shot = OneShot::new(TIMEOUT);
+        tap(&mut one_shot, ONE_SHOT_SHIFT, 0);
+        tap(&mut one_shot, b::MODIFIERKEY_LEFT_CTRL, 1000);
+        assert_eq!(tap(&mut one_shot, b::KEY_A, 2000).0, SHIFT as u16);
+    }
+
+    #[test]
+    fn double_tap_locks_and_next_tap_unlocks() {
+        let mut one_shot = OneShot::new(TIMEOUT);
+        tap(&mut one_shot, ONE_SHOT_SHIFT, 0);
+        assert_eq!(tap(&mut one_shot, ONE_SHOT_SHIFT, 1000).1, SHIFT as u16);
+        assert_eq!(tap(&mut one_shot, b::KEY_A, 2000), (SHIFT as u16, SHIFT as u16));
+        assert!(!one_shot.update(2 * TIMEOUT));
+        assert_eq!(tap(&mut one_shot, ONE_SHOT_SHIFT, 2 * TIMEOUT).1, 0);
+        assert_eq!(tap(&mut one_shot, b::KEY_A, 2 * TIMEOUT + 1000), (0, 0));
+    }
+
+    #[test]
+    fn armed_key_times_out() {
+        let mut one_shot = OneShot::new(TIMEOUT);
+        tap(&mut one_shot, ONE_SHOT_SHIFT, 0);
+        assert!(!one_shot.update(TIMEOUT - 1));
+        assert!(one_shot.update(TIMEOUT + 500));
+        assert_eq!(tap(&mut one_shot, b::KEY_A, TIMEOUT + 1000), (0, 0));
+    }
+
+    #[test]
+    fn held_key_acts_as_modifier() {
+        let info = crate::custom_key_codes::extra_information_about_key_codes();
+        let mut one_shot = OneShot::new(TIMEOUT);
+        one_shot.process(&key_event(ONE_SHOT_SHIFT, true, 0), &info);
+        assert_eq!(one_shot.modifiers(), SHIFT as u16);
+        assert_eq!(tap(&mut one_shot, b::KEY_A, 1000), (SHIFT as u16, SHIFT as u16));
+        // Key was used while held, so it is not armed after release
+        one_shot.process(&key_event(ONE_SHOT_SHIFT, false, 2000), &info);
+        assert_eq!(tap(&mut one_shot, b::KEY_B, 3000), (0, 0));
+    }
+
+    #[test]
+    fn consumed_key_stays_until_consuming_key_is_released() {
+        let info = crate::custom_key_codes::extra_information_about_key_codes();
+        let mut one_shot = OneShot::new(TIMEOUT);
+        tap(&mut one_shot, ONE_SHOT_SHIFT, 0);
+        one_shot.process(&key_event(b::KEY_A, true, 1000), &info);
+        // Other keys, released while A is held, do not end it
+        assert_eq!(tap(&mut one_shot, b::KEY_B, 2000), (SHIFT as u16, SHIFT as u16));
+        // Consumed key does not time out
+        assert!(!one_shot.update(2 * TIMEOUT));
+        one_shot.process(&key_event(b::KEY_A, false, 2 * TIMEOUT), &info);
+        assert_eq!(one_shot.modifiers(), 0);
+    }
+}
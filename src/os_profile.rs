@@ -0,0 +1,197 @@
+//! Host operating system profiles. The same keyboard can be used on Linux, Windows and macOS,
+//! which expect some keys in different places, e.g. Cmd of macOS is next to space, where Alt is
+//! on PC keyboards. Profile remaps the keys after `categorize_key_presses`. Key is remapped when
+//! it is pressed, and selecting another profile does not change keys that are held.
+//!
+//! Profile is selected with keys from `profile_key`, which can be put for example behind a combo
+//! or a leader sequence. By default, leader sequences O L, O W and O M select Linux, Windows and
+//! Mac, see `LEADER_SEQUENCES` in `custom_key_codes.rs`. The selected profile is saved to EEPROM.
+
+use heapless::Vec; // fixed capacity `std::Vec`
+
+use crate::eeprom;
+use crate::process_keys::{ExtraKeyInfo, KeyCode};
+use crate::selection::{selection_key, Selection};
+use crate::ShortVec;
+
+/// Byte mask of profile selection keys, i.e. the second byte of their key code
+pub const PROFILE_KEY_MASK: u8 = 0xED;
+
+/// Recognizes saved profile in EEPROM
+const MAGIC: u8 = 0x05;
+
+/// Host operating system
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum OsProfile {
+    Linux = 0,
+    Windows = 1,
+    Mac = 2,
+}
+
+/// Profiles in the order of their discriminants
+const PROFILES: [OsProfile; 3] = [OsProfile::Linux, OsProfile::Windows, OsProfile::Mac];
+
+/// Key code that selects profile
+pub const fn profile_key(profile: OsProfile) -> u32 {
+    return selection_key(PROFILE_KEY_MASK, profile as u8);
+}
+
+/// Remappings of one profile
+#[derive(Debug)]
+pub struct ProfileMap {
+    /// Modifier key replacements, e.g. `(b::MODIFIERKEY_LEFT_ALT, b::MODIFIERKEY_LEFT_GUI)`.
+    /// Swapping two modifiers needs both directions.
+    pub modifiers: &'static [(u32, u32)],
+    /// Regular key replacements (key, replacement, modifiers added with the replacement)
+    pub keys: &'static [(u32, u32, u32)],
+    /// Media key replacements (media key, replacement)
+    pub media: &'static [(u32, u32)],
+}
+
+/// Selected profile and remappings of all profiles
+#[derive(Debug)]
+pub struct OsProfiles {
+    pub profile: Selection<OsProfile>,
+    /// Remappings of Linux, Windows and Mac, in this order
+    maps: &'static [ProfileMap; 3],
+    /// Pressed modifiers and their replacements, as bits of `modifier_slots`
+    pressed_modifiers: ShortVec<(u16, u16)>,
+    /// Pressed regular keys, their replacements and modifiers added with the replacements
+    pressed_keys: ShortVec<(u8, u8, u16)>,
+}
+
+impl OsProfiles {
+    /// Create profiles, and load the selected profile from EEPROM
+    pub fn new(maps: &'static [ProfileMap; 3]) -> OsProfiles {
+        let profile = Selection::load(
+            "Host OS profile",
+            &PROFILES,
+            PROFILE_KEY_MASK,
+            eeprom::OS_PROFILE_ADDR,
+            MAGIC,
+        );
+        return OsProfiles {
+            profile,
+            maps,
+            pressed_modifiers: Vec::new(),
+            pressed_keys: Vec::new(),
+        };
+    }
+
+    fn map(&self) -> &'static ProfileMap {
+        return &self.maps[self.profile.index()];
+    }
+
+    /// Remap keys according to the selected profile, and return remapped modifiers. Replacement
+    /// is decided when key is pressed, and kept until it is released.
+    /// # Arguments
+    /// * `modifier_slots` Pressed modifiers, as `modifier_slots` in `main`
+    /// * `regular_keys`   Pressed regular keys, from `categorize_key_presses`
+    pub fn remap(
+        &mut self,
+        modifier_slots: u16,
+        regular_keys: &mut ShortVec<KeyCode<u8>>,
+        info: &ExtraKeyInfo,
+    ) -> u16 {
+        let map = self.map();
+        let mut modifiers = 0;
+        let mut pressed_modifiers: ShortVec<(u16, u16)> = Vec::new();
+        for bit in (0..8).map(|i| 1u16 << i).filter(|&bit| modifier_slots & bit != 0) {
+            let replacement = match self.pressed_modifiers.iter().find(|&&(b, _)| b == bit) {
+                Some(&(_, replacement)) => replacement,
+                None => map.modifiers.iter()
+                    .find(|&&(from, _)| {
+                        from.to_le_bytes()[1] == info.modifier_key_mask && from as u16 & 0xFF == bit
+                    })
+                    .map_or(bit, |&(_, to)| to as u16 & 0xFF),
+            };
+            pressed_modifiers.push((bit, replacement)).unwrap_or(());
+            modifiers |= replacement;
+        }
+        let mut pressed_keys: ShortVec<(u8, u8, u16)> = Vec::new();
+        for k in regular_keys.iter_mut() {
+            let key = k.into_inner();
+            let (to, added) = match self.pressed_keys.iter().find(|&&(from, _, _)| from == key) {
+                Some(&(_, to, added)) => (to, added),
+                None => map.keys.iter()
+                    .find(|&&(from, _, _)| {
+                        from.to_le_bytes()[1] == info.regular_key_mask && from as u8 == key
+                    })
+                    .map_or((key, 0), |&(_, to, added)| (to as u8, added as u16 & 0xFF)),
+            };
+            *k = match *k {
+                KeyCode::Certain(_) => KeyCode::Certain(to),
+                KeyCode::Uncertain(_) => KeyCode::Uncertain(to),
+            };
+            pressed_keys.push((key, to, added)).unwrap_or(());
+            modifiers |= added;
+        }
+        self.pressed_modifiers = pressed_modifiers;
+        self.pressed_keys = pressed_keys;
+        return if modifiers == 0 { 0 } else { 0xE000 | modifiers };
+    }
+
+    /// Remap media key according to the selected profile
+    pub fn remap_media(&self, media_key: u32) -> u32 {
+        return self.map().media.iter()
+            .find(|&&(from, _)| from == media_key)
+            .map_or(media_key, |&(_, to)| to);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use teensy3::bindings as b;
+
+    const MAPS: [ProfileMap; 3] = [
+        ProfileMap { modifiers: &[], keys: &[], media: &[] },
+        ProfileMap { modifiers: &[], keys: &[], media: &[] },
+        ProfileMap {
+            modifiers: &[(b::MODIFIERKEY_LEFT_ALT, b::MODIFIERKEY_LEFT_GUI)],
+            keys: &[(b::KEY_PRINTSCREEN, b::KEY_4, b::MODIFIERKEY_LEFT_SHIFT)],
+            media: &[],
+        },
+    ];
+
+    fn keys(codes: &[u32]) -> ShortVec<KeyCode<u8>> {
+        return codes.iter().map(|&c| KeyCode::Certain(c as u8)).collect();
+    }
+
+    #[test]
+    fn held_keys_keep_profile_of_press() {
+        let info = crate::custom_key_codes::extra_information_about_key_codes();
+        let alt = b::MODIFIERKEY_LEFT_ALT as u16;
+        let mut profiles = OsProfiles::new(&MAPS);
+        profiles.profile.select(profile_key(OsProfile::Mac));
+        let mut regular = keys(&[b::KEY_PRINTSCREEN]);
+        assert_eq!(profiles.remap(alt, &mut regular, &info),
+                   (b::MODIFIERKEY_LEFT_GUI | b::MODIFIERKEY_LEFT_SHIFT) as u16);
+        assert_eq!(regular, keys(&[b::KEY_4]));
+        // Keys stay remapped until released, even though profile is changed
+        profiles.profile.select(profile_key(OsProfile::Linux));
+        let mut regular = keys(&[b::KEY_PRINTSCREEN]);
+        assert_eq!(profiles.remap(alt, &mut regular, &info),
+                   (b::MODIFIERKEY_LEFT_GUI | b::MODIFIERKEY_LEFT_SHIFT) as u16);
+        let mut regular = keys(&[]);
+        assert_eq!(profiles.remap(0, &mut regular, &info), 0);
+        let mut regular = keys(&[b::KEY_PRINTSCREEN]);
+        assert_eq!(profiles.remap(alt, &mut regular, &info), alt);
+        assert_eq!(regular, keys(&[b::KEY_PRINTSCREEN]));
+    }
+
+    #[test]
+    fn only_regular_key_codes_are_remapped() {
+        // Key code 0x46 with the mask of some other key type is not Print Screen
+        const MAPS: [ProfileMap; 3] = [
+            ProfileMap { modifiers: &[], keys: &[(0xE946, b::KEY_4, 0)], media: &[] },
+            ProfileMap { modifiers: &[], keys: &[], media: &[] },
+            ProfileMap { modifiers: &[], keys: &[], media: &[] },
+        ];
+        let info = crate::custom_key_codes::extra_information_about_key_codes();
+        let mut profiles = OsProfiles::new(&MAPS);
+        let mut regular = keys(&[b::KEY_PRINTSCREEN]);
+        profiles.remap(0, &mut regular, &info);
+        assert_eq!(regular, keys(&[b::KEY_PRINTSCREEN]));
+    }
+}
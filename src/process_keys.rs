@@ -7,11 +7,22 @@
 //! implementation is quite sophisticated, and it detects all keys that are possible
 //! to be deteceted.
 use heapless::Vec; // fixed capacity `std::Vec`
+use typenum::U64 as PinsCap;
 
+use teensy3::bindings as b;
 use teensy3::pins::{Pin, PinMode, PinRow};
-use teensy3::util::delay;
 
-use super::{full_vec, ShortVec};
+use super::{delay_us, full_vec, ShortVec};
+use crate::eeprom;
+use crate::events::{push_event, EventQueue, KeyEvent};
+
+/// Magic byte marking valid settle times in EEPROM
+const SETTLE_TIMES_MAGIC: u8 = 0x5E;
+/// Magic byte marking valid debounce times in EEPROM
+const DEBOUNCE_TIMES_MAGIC: u8 = 0xDB;
+/// Size of one key in EEPROM: position (3) and debounce time (4)
+const DEBOUNCE_ENTRY_LEN: usize = 7;
+use crate::macros::Macro;
 
 /// KeyState corresponds to scan state of GPIO, accompanied with some extra information.
 /// If three or more keys are pressed, it is not sure whether all registered key
@@ -51,22 +62,95 @@ impl<T> KeyCode<T> {
     }
 }
 
+/// Matrix of key codes. Row and column indices correspond to `row_pins` and `col_pins` of
+/// `KeyMatrix`, and `None` marks that there is no key.
+pub type CodeMatrix = ShortVec<ShortVec<Option<u32>>>;
+
+/// Diodes of the key matrix. Laptop keyboard pads do not usually have any diodes, so three pressed
+/// corners of a rectangle in matrix also connect the fourth one, which leads to ghost presses.
+/// If each key has a diode, every key press is unambiguous, and the ghost analysis is skipped.
+/// Direction matters: current flows from voltage source pins to drain pins, so the pins on the
+/// anode side of diodes must be the sources.
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Diodes {
+    /// No diodes, i.e. ghost presses are possible.
+    Absent,
+    /// Diodes let current flow from row pins to column pins.
+    RowToCol,
+    /// Diodes let current flow from column pins to row pins.
+    ColToRow,
+    /// Duplex matrix, where each matrix item can have two keys with opposite diodes. The pins
+    /// alternate their roles between two scan passes: first rows are sources, then columns are.
+    /// This fits twice as many keys for the same pin count.
+    Duplex,
+}
+
 /// This is one central object of whole project. It is used to read GPIO pin connections and to
 /// output a list of pressed keys. The central function for that purpose is `scan_key_press`.
 #[derive(Debug)]
 pub struct KeyMatrix {
     /// Key code matrix
     pub code_matrix: ShortVec<ShortVec<Option<u32>>>,
+    /// Key code matrix for keys that conduct from column to row. Used only by duplex matrix.
+    pub reverse_code_matrix: Option<CodeMatrix>,
     /// Voltage source pins. Index corresponds row index in matrix.
+    /// (With `Diodes::ColToRow` the roles of rows and columns are swapped.)
     pub row_pins: ShortVec<Pin>,
     /// Voltage drain pins. Index corresponds column index in matrix.
     pub col_pins: ShortVec<Pin>,
-    /// Other less important fields in Key matrix
+    /// Diodes and their direction
+    pub diodes: Diodes,
+    /// Keys that are not part of the matrix, but connect a single pin to the ground. For example
+    /// separately wired mouse buttons or foot pedals. Tuple is (pin, key code).
+    pub direct_keys: ShortVec<(Pin, u32)>,
+    /// GPIO port numbers of all pins that are read, i.e. voltage sources and direct keys. These
+    /// are needed for pin interrupts.
+    pub source_pin_numbers: Vec<usize, PinsCap>,
+    /// Time (in microseconds) to wait after each drain, so that source pins charge back to full
+    /// voltage. By default it is one millisecond, which is plenty. Use `calibrate_settle_time`
+    /// to find out the minimal safe delay.
+    pub settle_time: u32,
+    /// Measured pull-up recovery times (in microseconds) of source pins, and then columns if the
+    /// matrix is duplex. Empty if not calibrated.
+    pub recovery_times: Vec<u32, PinsCap>,
+}
+
+/// Position of a key in the whole keyboard, which may consist of multiple key matrices. This is
+/// the unified namespace for keys, whichever matrix they happen to be in. Keys that do not have
+/// their own matrix item are given positions in extra rows after the last matrix row: first the
+/// reversed keys of duplex matrix (row `n_rows + row`), and then the direct keys (row `n_rows` or
+/// `2*n_rows`, where column is index in `direct_keys`).
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct KeyPos {
+    /// Index of key matrix in `KeyMatrices`
+    pub matrix: u8,
+    /// Row index in key matrix
+    pub row: u8,
+    /// Column index in key matrix
+    pub col: u8,
+}
+
+impl KeyPos {
+    pub fn new(matrix: u8, row: usize, col: usize) -> KeyPos {
+        KeyPos { matrix, row: row as u8, col: col as u8 }
+    }
+}
+
+/// The whole keyboard, which consists of one or more independent key matrices. For example, the
+/// main key pad, separate numpad and separate mouse buttons. Every matrix has its own pins and key
+/// codes, and the ghost analysis is done separately for each. Key presses of all matrices are
+/// merged in one list, so Fn-bindings and everything else apply to keys of any matrix.
+#[derive(Debug)]
+pub struct KeyMatrices {
+    /// Key matrices, index corresponds `KeyPos::matrix`
+    pub matrices: ShortVec<KeyMatrix>,
+    /// Other less important fields in keyboard
     pub info: ExtraKeyInfo,
 }
 
 #[derive(Debug)]
-/// Some extra information about key codes. This is not-so-interesting field of `KeyMatrix`
+/// Some extra information about key codes. This is not-so-interesting field of `KeyMatrices`
 pub struct ExtraKeyInfo {
     /// Fn key code
     pub fn_key: u32,
@@ -76,6 +160,17 @@ pub struct ExtraKeyInfo {
     pub regular_key_mask: u8,
     /// Byte masks for modifier keys.
     pub modifier_key_mask: u8,
+    /// Byte mask for macro keys, see `macros::macro_key`
+    pub macro_key_mask: u8,
+    /// Macros that are played by macro keys
+    pub macros: &'static [Macro],
+}
+
+impl ExtraKeyInfo {
+    /// Whether key code is modifier or Fn key
+    pub fn is_modifier(&self, code: u32) -> bool {
+        return code.to_le_bytes()[1] == self.modifier_key_mask || code == self.fn_key;
+    }
 }
 
 impl KeyMatrix {
@@ -84,29 +179,73 @@ impl KeyMatrix {
     /// * `mat`  Matrix of key codes
     /// * `rows` Vector, index corresponds row in matrix, and value corresponds GPIO port number
     /// * `cols` Vector, index corresponds column in matrix and, value corresponds GPIO port number
-    /// * `info` Information about key codes
+    /// * `diodes` Whether keys have diodes, and which way they are
     pub fn new(
         pinrow: &mut PinRow,
         code_matrix: ShortVec<ShortVec<Option<u32>>>,
         rows: ShortVec<usize>,
         cols: ShortVec<usize>,
-        info: ExtraKeyInfo,
+        diodes: Diodes,
     ) -> KeyMatrix {
-        let row_pins: ShortVec<Pin> = rows.iter().map(|&i| {
+        assert!(diodes != Diodes::Duplex, "Create duplex matrix with `KeyMatrix::new_duplex`.");
+        // With diodes from columns to rows, the columns are sources and rows are drains
+        let rows_are_sources = diodes != Diodes::ColToRow;
+        let mut get_pin = |i: usize, source: bool| if source {
             pinrow.get_pin(i, PinMode::InputPullup)
-        }).collect();
-        let col_pins: ShortVec<Pin> = cols.iter().map(|&j| {
-            let mut p = pinrow.get_pin(j, PinMode::OutputOpenDrain);
+        } else {
+            let mut p = pinrow.get_pin(i, PinMode::OutputOpenDrain);
             p.digital_write(true);  // By default disable drain
             p
-        }).collect();
-        return KeyMatrix { code_matrix, row_pins, col_pins, info};
+        };
+        let row_pins: ShortVec<Pin> = rows.iter().map(|&i| get_pin(i, rows_are_sources)).collect();
+        let col_pins: ShortVec<Pin> = cols.iter().map(|&j| get_pin(j, !rows_are_sources)).collect();
+        let direct_keys = Vec::new();
+        let reverse_code_matrix = None;
+        let source_pin_numbers = if rows_are_sources { &rows } else { &cols }.iter()
+            .copied().collect();
+        return KeyMatrix {
+            code_matrix, reverse_code_matrix, row_pins, col_pins, diodes, direct_keys,
+            source_pin_numbers, settle_time: 1000, recovery_times: Vec::new(),
+        };
     }
 
+    /// Create duplex key matrix, i.e. matrix where each matrix item can have two keys: one that
+    /// conducts from row to column (`code_matrix`), and other that conducts from column to row
+    /// (`reverse_code_matrix`). Other arguments are the same as in `new`.
+    #[allow(dead_code)]
+    pub fn new_duplex(
+        pinrow: &mut PinRow,
+        code_matrix: ShortVec<ShortVec<Option<u32>>>,
+        reverse_code_matrix: CodeMatrix,
+        rows: ShortVec<usize>,
+        cols: ShortVec<usize>,
+    ) -> KeyMatrix {
+        let mut mat = KeyMatrix::new(pinrow, code_matrix, rows, cols, Diodes::RowToCol);
+        mat.diodes = Diodes::Duplex;
+        mat.reverse_code_matrix = Some(reverse_code_matrix);
+        return mat;
+    }
 
-    /// Scan key matrix GPIO connections, and return list of currently pressed keys.
-    /// Return None if nothing is pressed.
-    pub fn scan_key_press(&mut self) -> Option<ShortVec<KeyCode<u32>>> {
+    /// Add keys that are wired directly between some pin and the ground. They are scanned along
+    /// with the matrix, and they are reported in the same list of key presses.
+    /// # Arguments
+    /// * `keys` List of (GPIO port number, key code)
+    pub fn add_direct_keys(&mut self, pinrow: &mut PinRow, keys: &[(usize, u32)]) {
+        for &(i, code) in keys.iter() {
+            let pin = pinrow.get_pin(i, PinMode::InputPullup);
+            self.direct_keys.push((pin, code)).unwrap_or_else(|_| panic!(
+                "Too many direct keys, the maximum is {}.", self.direct_keys.capacity()
+            ));
+            self.source_pin_numbers.push(i).unwrap();
+        }
+    }
+
+
+    /// Scan key matrix GPIO connections, and return list of currently pressed keys and their
+    /// positions. Return None if nothing is pressed.
+    /// # Arguments
+    /// * `matrix` Index of this matrix in `KeyMatrices`, which is used in key positions
+    pub fn scan_key_press(&mut self, matrix: u8) -> Option<ShortVec<(KeyPos, KeyCode<u32>)>> {
         // `mat` keeps book about what keys may be conflicted with other key presses.
         // Conflicts may occur if multiple keys are pressed at the same time.
         // By knowing what presses are "ghost" artifacts, they can be dropped out. Those that
@@ -114,13 +253,25 @@ impl KeyMatrix {
         let mut mat: ShortVec<ShortVec<KeyState>> =
             full_vec(full_vec(Free, self.col_pins.len()), self.row_pins.len());
         let mut erroneous_keys: ShortVec<(usize, usize)> = Vec::new(); // *potentially erroneous
-        // Performance: Delays takes about 9000 us and conflict detection about 50-100 us
-        for (col, drain) in self.col_pins.iter_mut().enumerate() {
+        let ghosts_possible = self.diodes == Diodes::Absent;
+        let settle_time = self.settle_time;
+        // With diodes from columns to rows, the columns are sources and rows are drains
+        let swapped = self.diodes == Diodes::ColToRow;
+        let (sources, drains) = if swapped {
+            (&self.col_pins, &mut self.row_pins)
+        } else {
+            (&self.row_pins, &mut self.col_pins)
+        };
+        // Performance: Delays take 9 * `settle_time`, which is about 9000 us if not calibrated, and
+        // conflict detection takes about 50-100 us
+        for (d, drain) in drains.iter_mut().enumerate() {
             drain.digital_write(false);  // enable drain
-            for (row, source) in self.row_pins.iter().enumerate() {
+            for (s, source) in sources.iter().enumerate() {
                 let pressed = !source.digital_read();  // check if connected
                 if pressed {
+                    let (row, col) = if swapped { (d, s) } else { (s, d) };
                     mat[row][col] = match self.code_matrix[row][col] {
+                        Some(c) if !ghosts_possible => Pressed(c),  // Diodes block all ghosts
                         Some(c) => {
                             let conflict = scan_for_conflicts(&mut mat, row, col, true);
                             if conflict { Maybe(c) } else { Pressed(c) }
@@ -136,21 +287,33 @@ impl KeyMatrix {
                 }
             }
             drain.digital_write(true); // disable drain
-            delay(1); // It takes time for pullup pin to charge back to full voltage
+            delay_us(settle_time); // It takes time for pullup pin to charge back to full voltage
         }
         erroneous_keys.into_iter()
-            .filter(|&(i, j)| !scan_for_conflicts(&mut mat, i, j, false))
+            .filter(|&(i, j)| !ghosts_possible || !scan_for_conflicts(&mut mat, i, j, false))
             .for_each(|(i, j)| {
                 println!("Warning! Detected pin connection correspondin to matrix element ({}, {}),\
                     \nwhich does not have any key assigned in the key matrix.", i, j);
             });
-        let keys: ShortVec<KeyCode<u32>> = mat.iter()
-            .flatten()
-            .filter_map(|k| match *k {
-                Pressed(c) => Some(Certain(c)),
-                Maybe(c) => Some(Uncertain(c)),
+        let mut keys: ShortVec<(KeyPos, KeyCode<u32>)> = mat.iter()
+            .enumerate()
+            .flat_map(|(row, r)| r.iter().enumerate().map(move |(col, k)| (row, col, *k)))
+            .filter_map(|(row, col, k)| match k {
+                Pressed(c) => Some((KeyPos::new(matrix, row, col), Certain(c))),
+                Maybe(c) => Some((KeyPos::new(matrix, row, col), Uncertain(c))),
                 _ => None,
             }).collect();
+        let mut extra_row = self.row_pins.len();
+        if self.diodes == Diodes::Duplex {
+            self.scan_reverse_pass(matrix, &mut keys);
+            extra_row += self.row_pins.len();
+        }
+        // Direct keys can not have any ghosts, as they do not share pins with anything
+        for (col, (pin, code)) in self.direct_keys.iter().enumerate() {
+            if !pin.digital_read() {
+                keys.push((KeyPos::new(matrix, extra_row, col), Certain(*code))).unwrap_or(());
+            }
+        }
 
         return if keys.len() > 0 {
             Some(keys)
@@ -158,6 +321,211 @@ impl KeyMatrix {
             None
         };
     }
+
+    /// Measure how long it takes for each source pin to charge back to full voltage after it has
+    /// been pulled down, and set `settle_time` to the minimal safe delay. The pin is discharged
+    /// by using it as a drain for a moment, and then it is timed how long it takes for the pullup
+    /// to raise it back. The settle time is twice the slowest pin plus a small margin. Columns of
+    /// duplex matrix are sources in the reverse pass, so they are measured too.
+    pub fn calibrate_settle_time(&mut self) {
+        let timeout = 1000;  // microseconds, which is the default settle time
+        let sources = match self.diodes {
+            Diodes::ColToRow => &mut self.col_pins,
+            _ => &mut self.row_pins,
+        };
+        self.recovery_times.clear();
+        for source in sources.iter_mut() {
+            let elapsed = measure_recovery_time(source, timeout);
+            self.recovery_times.push(elapsed).unwrap();
+        }
+        if self.diodes == Diodes::Duplex {
+            for col in self.col_pins.iter_mut() {
+                let elapsed = measure_recovery_time(col, timeout);
+                self.recovery_times.push(elapsed).unwrap();
+                // Restore drain of the forward pass
+                col.set_mode(PinMode::OutputOpenDrain);
+                col.digital_write(true);
+            }
+        }
+        let slowest = self.recovery_times.iter().copied().max().unwrap_or(0);
+        self.settle_time = if slowest >= timeout {
+            timeout  // Some pin did not recover, so do not risk anything
+        } else {
+            2 * slowest + 2
+        };
+    }
+
+    /// Prepare matrix for sleeping so that any key press wakes up the controller, or restore it
+    /// back for scanning. When enabled, all drains are turned on at once, and `isr` is attached to
+    /// be called on falling edge of any source pin. Note that keys in the reverse direction of
+    /// duplex matrix can not wake up the controller, because the drains are on the wrong side.
+    pub fn set_wake_on_key_press(&mut self, enable: bool, isr: unsafe extern "C" fn()) {
+        let drains = match self.diodes {
+            Diodes::ColToRow => &mut self.row_pins,
+            _ => &mut self.col_pins,
+        };
+        // Drain enabled means writing `false`
+        drains.iter_mut().for_each(|p| p.digital_write(!enable));
+        for &i in self.source_pin_numbers.iter() {
+            unsafe {
+                if enable {
+                    b::attachInterrupt(i as u8, Some(isr), b::FALLING as i32);
+                } else {
+                    b::detachInterrupt(i as u8);
+                }
+            }
+        }
+    }
+
+    /// Second scan pass of duplex matrix. Columns are turned to voltage sources and rows to drains,
+    /// so that the keys conducting from column to row are found. Pin modes are restored afterwards.
+    fn scan_reverse_pass(&mut self, matrix: u8, keys: &mut ShortVec<(KeyPos, KeyCode<u32>)>) {
+        let reverse_code_matrix = match &self.reverse_code_matrix {
+            Some(m) => m,
+            None => return,
+        };
+        let n_rows = self.row_pins.len();
+        let settle_time = self.settle_time;
+        self.col_pins.iter_mut().for_each(|p| p.set_mode(PinMode::InputPullup));
+        self.row_pins.iter_mut().for_each(|p| {
+            p.set_mode(PinMode::OutputOpenDrain);
+            p.digital_write(true);  // By default disable drain
+        });
+        delay_us(settle_time);
+        for (row, drain) in self.row_pins.iter_mut().enumerate() {
+            drain.digital_write(false);  // enable drain
+            for (col, source) in self.col_pins.iter().enumerate() {
+                let pressed = !source.digital_read();  // check if connected
+                if pressed {
+                    match reverse_code_matrix[row][col] {
+                        // Duplex matrix has always diodes, so there can not be any ghosts
+                        Some(c) => {
+                            let pos = KeyPos::new(matrix, n_rows + row, col);
+                            keys.push((pos, Certain(c))).unwrap_or(());
+                        }
+                        None => println!("Warning! Detected reverse pin connection corresponding \
+                            to matrix element ({}, {}),\nwhich does not have any key assigned in \
+                            the reverse key matrix.", row, col),
+                    }
+                }
+            }
+            drain.digital_write(true); // disable drain
+            delay_us(settle_time); // It takes time for pullup pin to charge back to full voltage
+        }
+        self.row_pins.iter_mut().for_each(|p| p.set_mode(PinMode::InputPullup));
+        self.col_pins.iter_mut().for_each(|p| {
+            p.set_mode(PinMode::OutputOpenDrain);
+            p.digital_write(true);
+        });
+        delay_us(settle_time);
+    }
+}
+
+impl KeyMatrices {
+    /// Create keyboard without any key matrices. Add them with `add`.
+    pub fn new(info: ExtraKeyInfo) -> KeyMatrices {
+        return KeyMatrices { matrices: Vec::new(), info };
+    }
+
+    /// Add key matrix to keyboard. Its index in `KeyPos::matrix` is the number of matrices
+    /// added before it.
+    pub fn add(&mut self, mat: KeyMatrix) {
+        self.matrices.push(mat).unwrap_or_else(|_| panic!("Too many key matrices."));
+    }
+
+    /// Calibrate settle times of all key matrices, print the results, and store them to EEPROM.
+    /// If the settle time differs from the one stored on previous boot, it is printed too.
+    /// See `KeyMatrix::calibrate_settle_time`.
+    pub fn calibrate_settle_times(&mut self) {
+        let mut stored = [0u8; eeprom::SETTLE_TIMES_LEN];
+        eeprom::read(eeprom::SETTLE_TIMES_ADDR, &mut stored);
+        let valid = stored[0] == SETTLE_TIMES_MAGIC;
+        stored[0] = SETTLE_TIMES_MAGIC;
+        for (idx, mat) in self.matrices.iter_mut().enumerate() {
+            mat.calibrate_settle_time();
+            println!("Matrix {}: pull-up recovery times of source pins (us): {:?}",
+                     idx, mat.recovery_times);
+            if mat.settle_time >= 1000 {
+                println!("Warning! Some pin did not recover in time, using safe settle time.");
+            }
+            // Duplex matrix is scanned twice, the second time with rows as drains
+            let drains = match mat.diodes {
+                Diodes::Duplex => mat.col_pins.len() + mat.row_pins.len(),
+                Diodes::ColToRow => mat.row_pins.len(),
+                _ => mat.col_pins.len(),
+            };
+            println!("Matrix {}: settle time {} us, so one scan takes about {} us.",
+                     idx, mat.settle_time, mat.settle_time * drains as u32);
+            if let Some(bytes) = stored.get_mut(1 + 2 * idx..3 + 2 * idx) {
+                let previous = u16::from_le_bytes([bytes[0], bytes[1]]);
+                if valid && previous as u32 != mat.settle_time {
+                    println!("Matrix {}: settle time was {} us on previous boot.", idx, previous);
+                }
+                bytes.copy_from_slice(&(mat.settle_time as u16).to_le_bytes());
+            }
+        }
+        eeprom::write(eeprom::SETTLE_TIMES_ADDR, &stored);
+    }
+
+    /// Find position of key with given key code. If multiple keys have the same code, the first
+    /// one is returned.
+    pub fn position_of(&self, code: u32) -> Option<KeyPos> {
+        for (idx, mat) in self.matrices.iter().enumerate() {
+            let n_rows = mat.code_matrix.len();
+            let matrices = core::iter::once((0, &mat.code_matrix))
+                .chain(mat.reverse_code_matrix.iter().map(|m| (n_rows, m)));
+            for (row_offset, code_matrix) in matrices {
+                for (row, r) in code_matrix.iter().enumerate() {
+                    if let Some(col) = r.iter().position(|&c| c == Some(code)) {
+                        return Some(KeyPos::new(idx as u8, row_offset + row, col));
+                    }
+                }
+            }
+            let direct_row = if mat.reverse_code_matrix.is_some() { 2 * n_rows } else { n_rows };
+            if let Some(col) = mat.direct_keys.iter().position(|&(_, c)| c == code) {
+                return Some(KeyPos::new(idx as u8, direct_row, col));
+            }
+        }
+        return None;
+    }
+
+    /// Enable or disable waking up from sleep on key press on all key matrices.
+    /// See `KeyMatrix::set_wake_on_key_press`.
+    pub fn set_wake_on_key_press(&mut self, enable: bool, isr: unsafe extern "C" fn()) {
+        self.matrices.iter_mut().for_each(|mat| mat.set_wake_on_key_press(enable, isr));
+    }
+
+    /// Scan all key matrices, and return merged list of currently pressed keys and their
+    /// positions. Return None if nothing is pressed.
+    pub fn scan_key_press(&mut self) -> Option<ShortVec<(KeyPos, KeyCode<u32>)>> {
+        let mut keys: ShortVec<(KeyPos, KeyCode<u32>)> = Vec::new();
+        for (idx, mat) in self.matrices.iter_mut().enumerate() {
+            if let Some(matrix_keys) = mat.scan_key_press(idx as u8) {
+                // If capacity runs out, rest of the keys are dropped
+                matrix_keys.into_iter().for_each(|k| keys.push(k).unwrap_or(()));
+            }
+        }
+        return if keys.len() > 0 {
+            Some(keys)
+        } else {
+            None
+        };
+    }
+}
+
+/// Discharge pin, and measure time (in microseconds) that its pullup takes to raise it back.
+/// Gives up after `timeout`. Pin is left as pulled up input.
+fn measure_recovery_time(pin: &mut Pin, timeout: u32) -> u32 {
+    pin.set_mode(PinMode::OutputOpenDrain);
+    pin.digital_write(false);  // discharge
+    delay_us(10);
+    let start = unsafe { b::micros() };
+    pin.set_mode(PinMode::InputPullup);
+    let mut elapsed = 0;
+    while !pin.digital_read() && elapsed < timeout {
+        elapsed = unsafe { b::micros() }.wrapping_sub(start);
+    }
+    return elapsed;
 }
 
 fn scan_for_conflicts(
@@ -215,34 +583,188 @@ fn scan_for_conflicts(
 
 /// Debounce fixes common push button problem where quick "on-off" presses may be registered as
 /// two "on-off" presses. This phenomenon is caused by capasitance of circuit, which makes voltage
-/// somehow oscillate. The fix is to check that if an event (press/release) last only for 1-2 scan
-/// intervals, then continue that for 3 scan interval. So 10ms/20ms press/release becomes
-/// 30ms one. This functions assumes a simplification (and performance optimization) that no other
-/// keys are not operated at the same time. If other keys are operated, then debounce may happen.
-/// This function requires that the given history (scan1-scan3) is already result of this debounce.
-pub fn debounce(
-    scan0: Option<ShortVec<KeyCode<u32>>>,     // the most recent scan
-    scan1: &Option<ShortVec<KeyCode<u32>>>,    // the scan from last round
-    scan2: &Option<ShortVec<KeyCode<u32>>>,    // the scan two rounds ago
-    scan3: &Option<ShortVec<KeyCode<u32>>>,    // the scan three rounds ago
-) -> Option<ShortVec<KeyCode<u32>>> {
-    let scan0_len = scan0.as_ref().map_or(0, |vec| vec.len());
-    let scan1_len = scan1.as_ref().map_or(0, |vec| vec.len());
-
-    // Let "X" denote pressed, "O" released state, and in vector [scan3, scan2, scan1, scan0].
-
-    // Fix 1-interval case: [_, X, O, X] to [_, X, X, X] and [_, O, X, O] to [_, O, X, X] and
-    // fix 2-interval case: [X, O, O, X] to [X, O, O, O]  and [O, X, X, O] to [O, X, X, X].
-    // Note that every time former case is triggered, the latter is triggered on next round because
-    // already corrected history is fed for this function. So the minimum length of event is
-    // transformed first from 1 to 2, and on next round from 2 to 3.
-    if (&scan0 == scan2 && scan1_len != scan0_len)                        // 1-interval case
-        || (&scan0 == scan3 && scan1 == scan2 && scan1_len != scan0_len)   // 2-interval case
-    {
-        // Back-bounce detected, do not register the most recent scan, but repeat the previous one
-        return scan1.clone();
-    } else {
-        // No bounce detected, return the most recent scan as it is suppoded to be
-        return scan0;
+/// somehow oscillate. The fix is to keep every event (press/release) at least for `debounce_time`.
+/// Change of key state is registered immediately, but further changes of the same key are ignored
+/// until that time has passed. So with 30 ms debounce time, 10ms/20ms press/release becomes 30ms
+/// one. Every key is debounced separately, so operating other keys at the same time does not
+/// matter, and the scan rate does not affect the debounce time. Chattering keys can be given
+/// longer debounce time than the others, see `chatter`.
+#[derive(Debug)]
+pub struct Debouncer {
+    /// Minimum duration of press or release (in microseconds)
+    pub debounce_time: u32,
+    /// Keys that have longer debounce time than the default one, e.g. chattering keys
+    pub key_debounce_times: ShortVec<(KeyPos, u32)>,
+    /// Keys that are pressed, or that are released only recently
+    keys: ShortVec<DebouncedKey>,
+}
+
+/// Debounce state of one key
+#[derive(Debug, Copy, Clone)]
+struct DebouncedKey {
+    pos: KeyPos,
+    code: KeyCode<u32>,
+    pressed: bool,
+    /// Time of the last registered change (in microseconds)
+    changed: u32,
+    /// Debounce time of this key (in microseconds)
+    debounce_time: u32,
+    /// Time of the scan (in microseconds) since which the scanned state has differed from the
+    /// registered state, if it has. If the scanned state returns back before `debounce_time` has
+    /// passed, it was a bounce. Otherwise the change is registered with this time stamp, so that
+    /// the time spent waiting for debounce shows up in latency.
+    deviated: Option<u32>,
+}
+
+impl Debouncer {
+    pub fn new(debounce_time: u32) -> Debouncer {
+        return Debouncer { debounce_time, key_debounce_times: Vec::new(), keys: Vec::new() };
+    }
+
+    /// Debounce time of given key (in microseconds)
+    pub fn debounce_time_of(&self, pos: KeyPos) -> u32 {
+        return self.key_debounce_times.iter()
+            .find(|&&(p, _)| p == pos)
+            .map_or(self.debounce_time, |&(_, t)| t);
+    }
+
+    /// Set debounce time of single key (in microseconds). Returns `Err` if there are already too
+    /// many keys with custom debounce time.
+    pub fn set_debounce_time(&mut self, pos: KeyPos, debounce_time: u32) -> Result<(), ()> {
+        self.keys.iter_mut().filter(|k| k.pos == pos).for_each(|k| k.debounce_time = debounce_time);
+        match self.key_debounce_times.iter_mut().find(|(p, _)| *p == pos) {
+            Some((_, t)) => *t = debounce_time,
+            None => self.key_debounce_times.push((pos, debounce_time)).map_err(|_| ())?,
+        }
+        return Ok(());
+    }
+
+    /// Set all keys back to the default debounce time
+    pub fn reset_debounce_times(&mut self) {
+        let debounce_time = self.debounce_time;
+        self.key_debounce_times.clear();
+        self.keys.iter_mut().for_each(|k| k.debounce_time = debounce_time);
+    }
+
+    /// Load debounce times of individual keys from EEPROM, so that tuning of chattering keys is
+    /// kept over power off. If there are no valid debounce times, nothing is changed.
+    pub fn load_debounce_times(&mut self) {
+        let mut header = [0u8; 2];
+        eeprom::read(eeprom::DEBOUNCE_TIMES_ADDR, &mut header);
+        let [magic, n] = header;
+        if magic != DEBOUNCE_TIMES_MAGIC || n as usize > self.key_debounce_times.capacity() {
+            return;
+        }
+        for i in 0..n as usize {
+            let mut e = [0u8; DEBOUNCE_ENTRY_LEN];
+            eeprom::read(eeprom::DEBOUNCE_TIMES_ADDR + 2 + i * DEBOUNCE_ENTRY_LEN, &mut e);
+            let pos = KeyPos { matrix: e[0], row: e[1], col: e[2] };
+            let time = u32::from_le_bytes([e[3], e[4], e[5], e[6]]);
+            self.set_debounce_time(pos, time).unwrap_or(());
+        }
+    }
+
+    /// Save debounce times of individual keys to EEPROM
+    pub fn save_debounce_times(&self) {
+        let capacity = self.key_debounce_times.capacity();
+        assert!(2 + capacity * DEBOUNCE_ENTRY_LEN <= eeprom::DEBOUNCE_TIMES_LEN);
+        for (i, (pos, time)) in self.key_debounce_times.iter().enumerate() {
+            let mut e = [0u8; DEBOUNCE_ENTRY_LEN];
+            e[..3].copy_from_slice(&[pos.matrix, pos.row, pos.col]);
+            e[3..].copy_from_slice(&time.to_le_bytes());
+            eeprom::write(eeprom::DEBOUNCE_TIMES_ADDR + 2 + i * DEBOUNCE_ENTRY_LEN, &e);
+        }
+        let n = self.key_debounce_times.len() as u8;
+        eeprom::write(eeprom::DEBOUNCE_TIMES_ADDR, &[DEBOUNCE_TIMES_MAGIC, n]);
+    }
+
+    /// Compare the most recent scan to the debounced key states, and push the registered changes
+    /// to `events`. Changes that happen too soon after the previous change are bounces, and they
+    /// are ignored. Change in certainty of a press is not a bounce, and it is always registered.
+    /// Change that is held back until the debounce time has passed is time stamped with the scan
+    /// where it was first seen.
+    /// Returns positions of the keys that bounced, i.e. whose state changed and returned back
+    /// within `debounce_time`.
+    /// # Arguments
+    /// * `scan` The most recent scan
+    /// * `now`  Time of the scan (in microseconds)
+    pub fn debounce(
+        &mut self,
+        scan: Option<ShortVec<(KeyPos, KeyCode<u32>)>>,
+        now: u32,
+        events: &mut EventQueue,
+    ) -> ShortVec<KeyPos> {
+        let mut bounced: ShortVec<KeyPos> = Vec::new();
+        let scan = scan.unwrap_or_default();
+        let debounced = unsafe { b::micros() };
+        let default_time = self.debounce_time;
+        let key_debounce_times = &self.key_debounce_times;
+        let settled = |k: &DebouncedKey| now.wrapping_sub(k.changed) >= k.debounce_time;
+        let event = |pos, code, pressed, time| KeyEvent { pos, code, pressed, time, debounced };
+        let new_press = |pos, code| DebouncedKey {
+            pos, code, pressed: true, changed: now, deviated: None,
+            debounce_time: key_debounce_times.iter()
+                .find(|&&(p, _)| p == pos)
+                .map_or(default_time, |&(_, t)| t),
+        };
+
+        // Presses
+        for &(pos, code) in scan.iter() {
+            match self.keys.iter_mut().find(|k| k.pos == pos) {
+                Some(k) if k.pressed => {
+                    if k.deviated.is_some() {
+                        // Voltage bounced right after pressing the key, and it was ignored
+                        bounced.push(pos).unwrap_or(());
+                        k.deviated = None;
+                    }
+                    if k.code != code {
+                        k.code = code;
+                        push_event(events, event(pos, code, true, now));
+                    }
+                }
+                Some(k) => {
+                    if settled(k) {
+                        let time = k.deviated.unwrap_or(now);
+                        *k = new_press(pos, code);
+                        push_event(events, event(pos, code, true, time));
+                    } else {
+                        // Voltage may bounce back after releasing the key, ignore it for now
+                        k.deviated.get_or_insert(now);
+                    }
+                }
+                None => {
+                    if self.keys.push(new_press(pos, code)).is_ok() {
+                        push_event(events, event(pos, code, true, now));
+                    } else {
+                        println!("Warning! Too many keys pressed, ignoring key {:?}.", pos);
+                    }
+                }
+            }
+        }
+
+        // Releases
+        for k in self.keys.iter_mut().filter(|k| !scan.iter().any(|&(pos, _)| pos == k.pos)) {
+            if !k.pressed {
+                if k.deviated.is_some() {
+                    // Voltage bounced back after releasing the key, and it was ignored
+                    bounced.push(k.pos).unwrap_or(());
+                    k.deviated = None;
+                }
+            } else if settled(k) {
+                let time = k.deviated.take().unwrap_or(now);
+                k.pressed = false;
+                k.changed = now;
+                push_event(events, event(k.pos, k.code, false, time));
+            } else {
+                // Voltage may bounce right after pressing the key, ignore it for now
+                k.deviated.get_or_insert(now);
+            }
+        }
+
+        // Forget released keys that can not bounce anymore
+        if self.keys.iter().any(|k| !k.pressed && settled(k)) {
+            self.keys = self.keys.iter().filter(|k| k.pressed || !settled(k)).copied().collect();
+        }
+        return bounced;
     }
 }
@@ -11,7 +11,7 @@ use teensy3::{
     util::delay,
 };
 
-use crate::process_keys::{ExtraKeyInfo, KeyMatrix};
+use crate::process_keys::{CodeMatrix, Diodes, KeyMatrix};
 use crate::{full_vec, Contains, ShortVec};
 
 /// Utility tool that finds out key matrix. User presses through every single key through in
@@ -54,13 +54,16 @@ use crate::{full_vec, Contains, ShortVec};
 ///                 matrix can be directly copy pasted to source code. These names are also printed
 ///                 for each query in the key configuration process.
 ///
-/// * `info`:       Small extra information about key codes needed to control keyboard. See
-///                 `extra_information_about_key_codes` for more.
+/// * `diodes`:     Whether keys have diodes, and which direction they conduct. With diodes the
+///                 direction of each pin connection is detected too, and it decides which pins
+///                 are rows and which are columns. Without diodes the pins are classified by
+///                 reasoning, and the roles of pins are chosen somewhat arbitrarily. Duplex
+///                 matrix is classified the same way, and the direction of each connection tells
+///                 only in which of the two code matrices the key is stored.
 ///
 /// # Examples
 /// ```
 /// use teensy3::bindings as b;
-/// use crate::custom_key_codes::extra_information_about_key_codes;
 /// use crate::record_keyboard_matrix::figure_out_key_matrix;
 ///
 /// const KEY_NAMES_SHORT_TEST: &[&[&str]] = &[
@@ -76,20 +79,31 @@ use crate::{full_vec, Contains, ShortVec};
 /// ];
 ///
 /// let mut pinrow = unsafe{ PinRow::new_once()};
-/// let info = extra_information_about_key_codes();
-/// let mat = figure_out_key_matrix(&mut pinrow, KEY_CODES, KEY_NAMES, info);
+/// let mat = figure_out_key_matrix(&mut pinrow, KEY_CODES, KEY_NAMES, Diodes::Absent);
 /// ```
 #[allow(dead_code)]
 pub fn figure_out_key_matrix<'a>(
     pinrow: &mut PinRow,
     key_codes: &[&[u32]],
     key_names: &[&[&'a str]],
-    info: ExtraKeyInfo,
+    diodes: Diodes,
 ) -> KeyMatrix {
-    let mut keys = query_keys_from_user(pinrow, key_codes, key_names);
-    let (mut row_pins, mut col_pins) = separate_pins_to_rows_and_columns(&mut keys);
-    let code_matrix = build_and_print_code_matrix(&mut keys, &mut row_pins, &mut col_pins);
-    let mat = KeyMatrix::new(pinrow, code_matrix, row_pins, col_pins, info);
+    let mut keys = query_keys_from_user(pinrow, key_codes, key_names, diodes);
+    let recorded: Vec<(usize, usize), KeysCap> = keys.iter().map(|&(i, j, _, _)| (i, j)).collect();
+    let (mut row_pins, mut col_pins) = separate_pins_to_rows_and_columns(&mut keys, diodes);
+    // In duplex matrix, key conducts from column to row if its recorded source pin is a column
+    let reversed: Vec<bool, KeysCap> = keys.iter().zip(recorded.iter())
+        .map(|(&(i, _, _, _), &(source, _))| diodes == Diodes::Duplex && i != source)
+        .collect();
+    let (code_matrix, reverse_code_matrix) = build_and_print_code_matrix(
+        &mut keys, &mut row_pins, &mut col_pins, &reversed, diodes
+    );
+    let mat = match reverse_code_matrix {
+        Some(reverse_code_matrix) => KeyMatrix::new_duplex(
+            pinrow, code_matrix, reverse_code_matrix, row_pins, col_pins
+        ),
+        None => KeyMatrix::new(pinrow, code_matrix, row_pins, col_pins, diodes),
+    };
     return mat;
 }
 
@@ -97,6 +111,7 @@ fn query_keys_from_user<'a>(
     pinrow: &mut PinRow,
     key_codes: &[&[u32]],
     key_names: &[&[&'a str]],
+    diodes: Diodes,
 ) -> Vec<(usize, usize, u32, &'a str), KeysCap> {
     assert_eq!(key_codes[0].len(), 2,
         "First row in `key_codes` should contain only two keys, e.g. Backspace and Delete, \n\
@@ -131,7 +146,7 @@ fn query_keys_from_user<'a>(
         ];
         for ((&code, &name), &h) in codes.iter().zip(names.iter()).zip(helps.iter()) {
             print!("Press '{}'. {} ", name, h);
-            let (i, j) = wait_for_key(pinrow);
+            let (i, j) = wait_for_key(pinrow, diodes);
             println!("Ok.");
             keys.push((i, j, code, name)).unwrap();
             delay(200);
@@ -154,7 +169,7 @@ fn query_keys_from_user<'a>(
                 .zip(row_name).enumerate() {
                 delay(200);
                 print!("     Press key {}/{}: {} ", key_idx+1, row_code.len(), key_name);
-                let pair = wait_for_key(pinrow);
+                let pair = wait_for_key(pinrow, diodes);
                 if pair == delete {                                     // Skip key if it is broken
                     println!("Skipping that key.");
                 } else if pair == backspace {                           // Restart if typo is made
@@ -181,56 +196,77 @@ fn query_keys_from_user<'a>(
 /// Find out what to pins are electrically connected. This corresponds to key press. Scan pins
 /// by iterating ALL possible pin combinations, which is not very efficient, especially if key
 /// matrix is known. So use this only when you do not know how many columns and rows key matrix
-/// contains.
-pub fn scan_key_press(pinrow: &mut PinRow) -> Option<(usize, usize)> {
-    // Connected pins. There should be only ONE pin pair connected
-    let mut connection: Option<(usize, usize)> = None;
-
+/// contains. Returned pair is (source pin, drain pin). If keys have diodes, the connection is
+/// conducting only one way, so the pins are checked in both directions. Then the source pin is
+/// the one on the anode side of diode.
+pub fn scan_key_press(pinrow: &mut PinRow, diodes: Diodes) -> Option<(usize, usize)> {
     assert!(NUM_PINS <= PinsCap::to_usize(), "Allocated memory ran out, too many pins");
     // Set all pins to drain mode, but by default disable them. They will be turned on
     // only to check whether some particular connection exists
-    let mut pins: Vec<Pin, PinsCap> = (0..NUM_PINS).filter(|&i| i != LED_PIN)
+    let mut pins: Vec<(usize, Pin), PinsCap> = (0..NUM_PINS).filter(|&i| i != LED_PIN)
         .map(|i| {
             let mut p = pinrow.get_pin(i, PinMode::OutputOpenDrain);
             p.digital_write(true);  // By default disable drain
-            p
+            (i, p)
         })
         .collect();
-    // Check connections, and set drain pins one by one to source pins.
-    'l: for i in 0..pins.len() {
+    let mut connection = sweep_pin_pairs(&mut pins);
+    if diodes != Diodes::Absent {
+        // Sweep again in reverse order, so that every pin pair is checked also the other way round
+        pins.iter_mut().for_each(|(_, p)| {
+            p.set_mode(PinMode::OutputOpenDrain);
+            p.digital_write(true);
+        });
+        AsMut::<[(usize, Pin)]>::as_mut(&mut pins).reverse();
+        connection = match (connection, sweep_pin_pairs(&mut pins)) {
+            (Err(()), _) | (_, Err(())) => Err(()),
+            (Ok(Some(a)), Ok(Some(b))) => {
+                println!("Warning! Multiple connections found: {:?} and {:?}. Ignoring both.", a, b);
+                Err(())
+            }
+            (Ok(a), Ok(b)) => Ok(a.or(b)),
+        };
+    }
+    pins.into_iter().for_each(|(_, pin)| pinrow.return_pin(pin));
+    return connection.unwrap_or(None);
+}
+
+/// Check connections between all pin pairs, and set drain pins one by one to source pins. Pin
+/// which comes first in `pins` is source and the latter is drain. Returns `Err` if multiple
+/// connections are found.
+fn sweep_pin_pairs(pins: &mut [(usize, Pin)]) -> Result<Option<(usize, usize)>, ()> {
+    // Connected pins. There should be only ONE pin pair connected
+    let mut connection: Option<(usize, usize)> = None;
+    for i in 0..pins.len() {
         // Pins [0..i+1] are source pins "i", and [i+1..NUM_PINS] are drain pins "j"
         let (i_pins, j_pins) = pins.split_at_mut(i+1);
-        let pin_i = &mut i_pins[i];
+        let (i_real_idx, pin_i) = &mut i_pins[i];
         pin_i.set_mode(PinMode::InputPullup);  // Make `pin_i` voltage source
         delay(1);
-        for (j, pin_j) in j_pins.iter_mut().enumerate() {
+        for (j_real_idx, pin_j) in j_pins.iter_mut() {
             pin_j.digital_write(false);  // enable drain
             let pressed = !pin_i.digital_read();  // check if `pin_i` and `pin_j` are connected
             pin_j.digital_write(true);  // disable drain
             if pressed {delay(4);}  // It takes time for pullup pin to charge back!
 
-            let i_real_idx = if i < LED_PIN {i} else {i+1};
-            let j_real_idx = if i+1+j < LED_PIN {i+1+j} else {i+2+j};
             if pressed {
                 if connection == None {
-                    connection = Some((i_real_idx, j_real_idx));
+                    connection = Some((*i_real_idx, *j_real_idx));
                 } else {
                     println!("Warning! Multiple connections found: {:?} and {:?}. Ignoring both.",
-                             connection.unwrap(), (i_real_idx, j_real_idx));
-                    connection = None;
-                    break 'l;
+                             connection.unwrap(), (*i_real_idx, *j_real_idx));
+                    return Err(());
                 }
             }
         }
     }
-    pins.into_iter().for_each(|pin| pinrow.return_pin(pin));
-    return connection;
+    return Ok(connection);
 }
 
 /// Loops until some key is pressed
-pub fn wait_for_key(pinrow: &mut PinRow) -> (usize, usize) {
+pub fn wait_for_key(pinrow: &mut PinRow, diodes: Diodes) -> (usize, usize) {
     let pair = loop {
-        match scan_key_press(pinrow) {
+        match scan_key_press(pinrow, diodes) {
             Some(pair) => {break pair;},
             None => {delay(10);},
         }
@@ -245,9 +281,17 @@ pub fn wait_for_key(pinrow: &mut PinRow) -> (usize, usize) {
 /// and classifies pins to rows or columns if it is known that the counterpart is row/column.
 /// Sometimes pin can be chosen either way without contradictions, and then classification is
 /// done to balance row/column count.
+///
+/// If keys have diodes, the classification is not needed, because the direction of each pin
+/// connection is known, and it tells directly which pin is row and which is column. That is
+/// not the case with duplex matrix, where pins are used in both directions.
 fn separate_pins_to_rows_and_columns(
     keys: &mut Vec<(usize, usize, u32, &str), KeysCap>,
+    diodes: Diodes,
 ) -> (ShortVec<usize>, ShortVec<usize>) {
+    if diodes == Diodes::RowToCol || diodes == Diodes::ColToRow {
+        return separate_pins_by_diode_direction(keys, diodes);
+    }
     // row_pins: Index is row in matrix and value is pin number
     let mut row_pins: ShortVec<usize> = Vec::new();
     // col_pins: Index is column in matrix and value is pin number
@@ -382,11 +426,48 @@ fn separate_pins_to_rows_and_columns(
     return (row_pins, col_pins);
 }
 
+/// Separate pins to rows and columns when keys have diodes. Each pair in `keys` is
+/// (source pin, drain pin), so the diode direction decides which one of them is row.
+fn separate_pins_by_diode_direction(
+    keys: &mut Vec<(usize, usize, u32, &str), KeysCap>,
+    diodes: Diodes,
+) -> (ShortVec<usize>, ShortVec<usize>) {
+    let mut row_pins: ShortVec<usize> = Vec::new();
+    let mut col_pins: ShortVec<usize> = Vec::new();
+
+    for (i, j, _, _) in keys.iter_mut() {
+        // Make `i` the row pin and `j` the column pin
+        if diodes == Diodes::ColToRow {
+            core::mem::swap(i, j);
+        }
+        if col_pins.iter().contains(i) || row_pins.iter().contains(j) {
+            panic!("Error, some pin is both source and drain. Are the diodes set correctly? \
+                    If the matrix is duplex, use `Diodes::Duplex`.");
+        }
+        for (pins, pin) in [(&mut row_pins, *i), (&mut col_pins, *j)].iter_mut() {
+            if !pins.iter().contains(pin) {
+                pins.push(*pin).unwrap_or_else(|_| panic!(
+                    "Number of pins overflowed the maximum of key matrix dimension {}.",
+                    pins.capacity()
+                ));
+            }
+        }
+    }
+
+    // Sort them. (Syntax is ugly because vector is sorted by using slice cast.)
+    AsMut::<[usize]>::as_mut(&mut row_pins).sort_unstable();
+    AsMut::<[usize]>::as_mut(&mut col_pins).sort_unstable();
+
+    return (row_pins, col_pins);
+}
+
 fn build_and_print_code_matrix(
     keys: &mut Vec<(usize, usize, u32, &str), KeysCap>,
     row_pins: &mut ShortVec<usize>,
     col_pins: &mut ShortVec<usize>,
-) -> ShortVec<ShortVec<Option<u32>>> {
+    reversed: &Vec<bool, KeysCap>,
+    diodes: Diodes,
+) -> (CodeMatrix, Option<CodeMatrix>) {
     // The inverses of `row_pins` and `col_pins`.
     // That is, index corresponds index of pin, and value corresponds the row/column in matrix
     let mut pin_rows: Vec<Option<usize>, PinsCap> = full_vec(None, NUM_PINS);
@@ -410,18 +491,25 @@ fn build_and_print_code_matrix(
         "Internal error! Overlap with input and output pins."
     );
 
-    let mut code_matrix: ShortVec<ShortVec<Option<u32>>> =
-        full_vec(full_vec(None, col_pins.len()), row_pins.len());
-    let mut name_matrix: ShortVec<ShortVec<Option<&str>>> =
-        full_vec(full_vec(None, col_pins.len()), row_pins.len());
+    // Duplex matrix has two keys in each matrix item, one for both directions of current. Index 0
+    // is for the regular matrix, and index 1 for the keys conducting from column to row.
+    let duplex = diodes == Diodes::Duplex;
+    let mut code_matrices: [CodeMatrix; 2] = [
+        full_vec(full_vec(None, col_pins.len()), row_pins.len()),
+        full_vec(full_vec(None, col_pins.len()), row_pins.len()),
+    ];
+    let mut name_matrices: [ShortVec<ShortVec<Option<&str>>>; 2] = [
+        full_vec(full_vec(None, col_pins.len()), row_pins.len()),
+        full_vec(full_vec(None, col_pins.len()), row_pins.len()),
+    ];
     let mut column_max_width: ShortVec<usize>  // Width for each column for pretty printing
         = full_vec(usize::MIN, col_pins.len());
 
-    for &(i, j, code, name) in keys.iter() {
+    for (&(i, j, code, name), &rev) in keys.iter().zip(reversed.iter()) {
         let i_idx = pin_rows[i].unwrap();
         let j_idx = pin_cols[j].unwrap();
-        let code_cell = &mut code_matrix[i_idx][j_idx];
-        let name_cell = &mut name_matrix[i_idx][j_idx];
+        let code_cell = &mut code_matrices[rev as usize][i_idx][j_idx];
+        let name_cell = &mut name_matrices[rev as usize][i_idx][j_idx];
         assert!(name_cell.is_none(), "Clash for same matrix item! ({},{}) {} and {}",
                 i, j, name_cell.unwrap(), name);  // This is checked before, should never happen
         *code_cell = Some(code);
@@ -430,22 +518,35 @@ fn build_and_print_code_matrix(
     }
 
     println!("Here's key matrix. You can copy-paste it to source code.\n");
-    println!("let code_matrix = [");
-    for row in name_matrix.iter() {
-        print!("    [");
-        for (name, width) in row.iter().zip(column_max_width.iter()) {
-            print!("{name:>width$}, ", name=name.unwrap_or("0"), width=width);
+    let variables = ["code_matrix", "reverse_code_matrix"];
+    for (name_matrix, variable) in name_matrices.iter().zip(variables.iter())
+        .take(if duplex { 2 } else { 1 }) {
+        println!("let {} = [", variable);
+        for row in name_matrix.iter() {
+            print!("    [");
+            for (name, width) in row.iter().zip(column_max_width.iter()) {
+                print!("{name:>width$}, ", name=name.unwrap_or("0"), width=width);
+            }
+            println!("],");
         }
-        println!("],");
+        println!(
+            "].iter()\n    \
+                .map(|v| v.iter().map(|&k| if k==0 {{ None }} else {{ Some(k) }}).collect())\n    \
+                .collect();"
+        );
     }
     println!(
-        "].iter()\n    \
-            .map(|v| v.iter().map(|&k| if k==0 {{ None }} else {{ Some(k) }}).collect())\n    \
-            .collect();\n\
-        let rows = Vec::from_slice(&{:?}).unwrap();\n\
-        let cols = Vec::from_slice(&{:?}).unwrap();\n\
-        let mat = KeyMatrix::new(pinrow, code_matrix, rows, cols, info);",
+        "let rows = Vec::from_slice(&{:?}).unwrap();\n\
+        let cols = Vec::from_slice(&{:?}).unwrap();",
         row_pins, col_pins
     );
-    return code_matrix;
+    if duplex {
+        println!("let mat = KeyMatrix::new_duplex(\n    \
+            pinrow, code_matrix, reverse_code_matrix, rows, cols\n);");
+    } else {
+        println!("let mat = KeyMatrix::new(pinrow, code_matrix, rows, cols, Diodes::{:?});",
+                 diodes);
+    }
+    let [code_matrix, reverse_code_matrix] = code_matrices;
+    return (code_matrix, if duplex { Some(reverse_code_matrix) } else { None });
 }
@@ -0,0 +1,104 @@
+//! Settings that are selected with special key codes and saved to EEPROM, e.g. host OS profile
+//! and letter layout. Each setting has its own byte mask, i.e. the second byte of its selection
+//! key codes, and the first byte is the index of the selected option.
+
+use core::fmt::Debug;
+
+use crate::eeprom;
+
+/// Key code that selects n:th option of setting
+pub const fn selection_key(key_mask: u8, index: u8) -> u32 {
+    return (key_mask as u32) << 8 | index as u32;
+}
+
+/// Option of setting that is selected with keys, and saved to EEPROM
+#[derive(Debug)]
+pub struct Selection<T: 'static> {
+    /// Name that is printed when option is selected
+    name: &'static str,
+    /// All options. The first one is the default.
+    options: &'static [T],
+    /// Byte mask of selection keys
+    key_mask: u8,
+    /// Address of the saved selection in EEPROM, see `eeprom`
+    addr: usize,
+    /// Recognizes saved selection in EEPROM
+    magic: u8,
+    index: u8,
+}
+
+impl<T: Copy + Debug> Selection<T> {
+    /// Create setting, and load the selected option from EEPROM
+    pub fn load(
+        name: &'static str,
+        options: &'static [T],
+        key_mask: u8,
+        addr: usize,
+        magic: u8,
+    ) -> Selection<T> {
+        let mut buf = [0u8; 2];
+        eeprom::read(addr, &mut buf);
+        let index = saved_index(buf, magic, options.len()).unwrap_or(0);
+        let selection = Selection { name, options, key_mask, addr, magic, index };
+        println!("{}: {:?}", name, selection.get());
+        return selection;
+    }
+
+    /// Selected option
+    pub fn get(&self) -> T {
+        return self.options[self.index as usize];
+    }
+
+    /// Index of the selected option
+    pub fn index(&self) -> usize {
+        return self.index as usize;
+    }
+
+    /// Select option by selection key code, and save it to EEPROM. Other key codes are ignored.
+    pub fn select(&mut self, code: u32) {
+        let bytes = code.to_le_bytes();
+        let index = bytes[0];
+        if bytes[1] == self.key_mask && (index as usize) < self.options.len() {
+            self.index = index;
+            eeprom::write(self.addr, &[self.magic, index]);
+            println!("{}: {:?}", self.name, self.get());
+        }
+    }
+}
+
+/// Index of option saved in EEPROM bytes, or None if they are not valid
+fn saved_index(bytes: [u8; 2], magic: u8, n_options: usize) -> Option<u8> {
+    return match bytes {
+        [m, n] if m == magic && (n as usize) < n_options => Some(n),
+        _ => None,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPTIONS: [char; 3] = ['a', 'b', 'c'];
+
+    #[test]
+    fn saved_index_is_validated() {
+        assert_eq!(saved_index([0x1A, 2], 0x1A, 3), Some(2));
+        assert_eq!(saved_index([0x1A, 3], 0x1A, 3), None);
+        // Fresh EEPROM
+        assert_eq!(saved_index([0xFF, 0xFF], 0x1A, 3), None);
+    }
+
+    #[test]
+    fn select_by_key_of_own_mask() {
+        let mut selection = Selection {
+            name: "Test", options: &OPTIONS, key_mask: 0xEE, addr: 0, magic: 0x1A, index: 0,
+        };
+        assert_eq!(selection.get(), 'a');
+        selection.select(selection_key(0xEE, 2));
+        assert_eq!((selection.get(), selection.index()), ('c', 2));
+        // Keys of other settings and unknown options are ignored
+        selection.select(selection_key(0xED, 1));
+        selection.select(selection_key(0xEE, 3));
+        assert_eq!(selection.get(), 'c');
+    }
+}
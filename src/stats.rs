@@ -0,0 +1,167 @@
+//! Key press statistics. Counters are kept for every key position, and they are stored to EEPROM
+//! periodically, so that they accumulate over reboots. With these it is possible to spot worn
+//! keys (lots of chatter) and to decide on remaps (lots of presses).
+//!
+//! Statistics are printed with serial command 's', and `render_heat_map.py` renders them on the
+//! host as an ASCII heat map shaped like the keyboard layout.
+
+use heapless::Vec; // fixed capacity `std::Vec`
+use typenum::U96 as StatsCap; // Maximum number of keys with statistics
+
+use crate::eeprom;
+use crate::events::KeyEvent;
+use crate::process_keys::{KeyCode, KeyMatrices, KeyPos};
+
+/// Magic byte marking valid statistics in EEPROM
+const MAGIC: u8 = 0x5A;
+/// Size of one key in EEPROM: position (3), presses (4), chatter, uncertain and dropped (2 each)
+const ENTRY_LEN: usize = 13;
+
+/// Counters of one key
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct KeyCounters {
+    /// Registered presses
+    pub presses: u32,
+    /// Bounces that debouncing has ignored
+    pub chatter: u16,
+    /// Presses that were uncertain because of ghosting
+    pub uncertain: u16,
+    /// Presses that did not fit in the six key slots of USB report
+    pub dropped: u16,
+}
+
+/// Counters of all keys that have been used
+#[derive(Debug)]
+pub struct KeyStats {
+    keys: Vec<(KeyPos, KeyCounters), StatsCap>,
+    /// Whether there are changes that are not saved to EEPROM
+    pub unsaved: bool,
+}
+
+impl KeyStats {
+    pub fn new() -> KeyStats {
+        KeyStats { keys: Vec::new(), unsaved: false }
+    }
+
+    /// Counters of given key. If key does not have counters yet, they are created. Returns None
+    /// if there is no more room.
+    fn counters(&mut self, pos: KeyPos) -> Option<&mut KeyCounters> {
+        self.unsaved = true;
+        let idx = match self.keys.iter().position(|&(p, _)| p == pos) {
+            Some(idx) => idx,
+            None => {
+                self.keys.push((pos, KeyCounters::default())).ok()?;
+                self.keys.len() - 1
+            }
+        };
+        return Some(&mut self.keys[idx].1);
+    }
+
+    /// Count presses and uncertain presses of event. `was_held` tells whether the key was already
+    /// pressed before the event, in which case the event is only a change in certainty.
+    pub fn record_event(&mut self, event: &KeyEvent, was_held: bool) {
+        if !event.pressed {
+            return;
+        }
+        if let Some(c) = self.counters(event.pos) {
+            if !was_held {
+                c.presses = c.presses.saturating_add(1);
+            }
+            if let KeyCode::Uncertain(_) = event.code {
+                c.uncertain = c.uncertain.saturating_add(1);
+            }
+        }
+    }
+
+    /// Count one bounce that debouncing has ignored
+    pub fn record_chatter(&mut self, pos: KeyPos) {
+        if let Some(c) = self.counters(pos) {
+            c.chatter = c.chatter.saturating_add(1);
+        }
+    }
+
+    /// Count one press that did not fit in USB report
+    pub fn record_dropped(&mut self, pos: KeyPos) {
+        if let Some(c) = self.counters(pos) {
+            c.dropped = c.dropped.saturating_add(1);
+        }
+    }
+
+    /// Forget all statistics
+    pub fn reset(&mut self) {
+        self.keys.clear();
+        self.unsaved = true;
+    }
+
+    /// Load statistics from EEPROM. If there are no valid statistics, they start from zero.
+    pub fn load() -> KeyStats {
+        let mut stats = KeyStats::new();
+        let mut header = [0u8; 2];
+        eeprom::read(eeprom::STATS_ADDR, &mut header);
+        let [magic, n] = header;
+        if magic != MAGIC || n as usize > stats.keys.capacity() {
+            return stats;
+        }
+        for i in 0..n as usize {
+            let mut e = [0u8; ENTRY_LEN];
+            eeprom::read(eeprom::STATS_ADDR + 2 + i * ENTRY_LEN, &mut e);
+            let pos = KeyPos { matrix: e[0], row: e[1], col: e[2] };
+            let counters = KeyCounters {
+                presses: u32::from_le_bytes([e[3], e[4], e[5], e[6]]),
+                chatter: u16::from_le_bytes([e[7], e[8]]),
+                uncertain: u16::from_le_bytes([e[9], e[10]]),
+                dropped: u16::from_le_bytes([e[11], e[12]]),
+            };
+            stats.keys.push((pos, counters)).unwrap();
+        }
+        return stats;
+    }
+
+    /// Save statistics to EEPROM
+    pub fn save(&mut self) {
+        assert!(2 + self.keys.capacity() * ENTRY_LEN <= eeprom::STATS_LEN);
+        for (i, (pos, c)) in self.keys.iter().enumerate() {
+            let mut e = [0u8; ENTRY_LEN];
+            e[..3].copy_from_slice(&[pos.matrix, pos.row, pos.col]);
+            e[3..7].copy_from_slice(&c.presses.to_le_bytes());
+            e[7..9].copy_from_slice(&c.chatter.to_le_bytes());
+            e[9..11].copy_from_slice(&c.uncertain.to_le_bytes());
+            e[11..13].copy_from_slice(&c.dropped.to_le_bytes());
+            eeprom::write(eeprom::STATS_ADDR + 2 + i * ENTRY_LEN, &e);
+        }
+        eeprom::write(eeprom::STATS_ADDR, &[MAGIC, self.keys.len() as u8]);
+        self.unsaved = false;
+    }
+
+    /// Print statistics in the shape of keyboard layout, so that the host can render them as a
+    /// heat map. Each layout row starts with line "ROW", and each key is line
+    /// "KEY <name> <presses> <chatter> <uncertain> <dropped>". Keys that are not in the layout
+    /// are printed in the last row with their positions as names.
+    /// # Arguments
+    /// * `layout_codes` Key codes of layout, e.g. `KEY_CODES` in `custom_key_codes`
+    /// * `layout_names` Names of keys, e.g. `KEY_NAMES` in `custom_key_codes`
+    pub fn print(&self, mats: &KeyMatrices, layout_codes: &[&[u32]], layout_names: &[&[&str]]) {
+        let mut in_layout: Vec<KeyPos, StatsCap> = Vec::new();
+        println!("STATS BEGIN");
+        for (codes, names) in layout_codes.iter().zip(layout_names.iter()) {
+            println!("ROW");
+            for (&code, &name) in codes.iter().zip(names.iter()) {
+                let pos = mats.position_of(code);
+                let c = pos
+                    .and_then(|pos| self.keys.iter().find(|&&(p, _)| p == pos))
+                    .map(|&(_, c)| c)
+                    .unwrap_or_default();
+                println!("KEY {} {} {} {} {}", name, c.presses, c.chatter, c.uncertain, c.dropped);
+                if let Some(pos) = pos {
+                    in_layout.push(pos).ok();
+                }
+            }
+        }
+        println!("ROW");
+        for (pos, c) in self.keys.iter().filter(|(pos, _)| !in_layout.iter().any(|p| p == pos)) {
+            println!("KEY {}/{}/{} {} {} {} {}",
+                     pos.matrix, pos.row, pos.col, c.presses, c.chatter, c.uncertain, c.dropped);
+        }
+        println!("STATS END");
+    }
+}
@@ -0,0 +1,97 @@
+//! Detection of stuck keys. If some pins are shorted, e.g. because of debris or spilled coffee,
+//! a key may read pressed continuously. Host would then auto-repeat it forever. Therefore keys
+//! that are pressed implausibly long are masked, until they are seen released.
+
+use heapless::Vec; // fixed capacity `std::Vec`
+
+use crate::events::KeyEvent;
+use crate::process_keys::{ExtraKeyInfo, KeyCode, KeyPos};
+use crate::ShortVec;
+
+/// Masks keys that have been pressed longer than timeout
+#[derive(Debug)]
+pub struct StuckKeys {
+    /// Timeout of regular keys (in microseconds), or None if regular keys are never masked.
+    /// Arrows, game keys and Backspace may be legitimately held for long.
+    pub timeout: Option<u32>,
+    /// Timeout of modifier keys and Fn key, which are legitimately held longer (in microseconds)
+    pub modifier_timeout: u32,
+    /// Number of keys that have been masked since start-up
+    pub count: u16,
+    /// Keys that are masked until they are released
+    masked: ShortVec<KeyPos>,
+}
+
+impl StuckKeys {
+    pub fn new(timeout: Option<u32>, modifier_timeout: u32) -> StuckKeys {
+        return StuckKeys {
+            timeout,
+            modifier_timeout,
+            count: 0,
+            masked: Vec::new(),
+        };
+    }
+
+    /// Returns false if event concerns masked key, and it should be ignored. Release of masked key
+    /// removes the mask.
+    pub fn filter_event(&mut self, event: &KeyEvent) -> bool {
+        if !event.pressed {
+            if let Some(i) = self.masked.iter().position(|&pos| pos == event.pos) {
+                self.masked.swap_remove(i);
+                println!("Stuck key {:?} is released", event.pos);
+                return false;
+            }
+            return true;
+        }
+        return !self.masked.contains(&event.pos);
+    }
+
+    /// Remove keys from `held` that have been pressed longer than their timeout. Returns true if
+    /// some key was masked.
+    /// # Arguments
+    /// * `held`        Currently pressed keys
+    /// * `press_times` Press times of keys, see `events::update_press_times`
+    /// * `now`         Current time (in microseconds)
+    /// * `info`        Information about key codes, used to tell modifier keys apart
+    pub fn mask_stuck_keys(
+        &mut self,
+        held: &mut ShortVec<(KeyPos, KeyCode<u32>)>,
+        press_times: &ShortVec<(KeyPos, u32)>,
+        now: u32,
+        info: &ExtraKeyInfo,
+    ) -> bool {
+        let mut masked_any = false;
+        let mut i = 0;
+        while i < held.len() {
+            let (pos, code) = held[i];
+            let timeout = if info.is_modifier(code.into_inner()) {
+                Some(self.modifier_timeout)
+            } else {
+                self.timeout
+            };
+            let stuck = match timeout {
+                Some(timeout) => press_times.iter()
+                    .any(|&(p, t)| p == pos && now.wrapping_sub(t) >= timeout),
+                None => false,
+            };
+            if stuck && self.masked.push(pos).is_ok() {
+                println!("Warning! Key {:?} has been pressed for {} s, ignoring it until it is \
+                          released.", pos, timeout.unwrap_or(0) / 1_000_000);
+                self.count = self.count.saturating_add(1);
+                held.swap_remove(i);
+                masked_any = true;
+            } else {
+                i += 1;
+            }
+        }
+        return masked_any;
+    }
+
+    /// Print currently masked keys
+    pub fn print(&self) {
+        println!("Stuck keys since start-up: {}", self.count);
+        for pos in self.masked.iter() {
+            println!("    {:?} is masked", pos);
+        }
+    }
+}
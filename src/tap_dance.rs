@@ -0,0 +1,251 @@
+//! Tap dance keys, whose action depends on how many times they are tapped, and whether the last
+//! tap is held. For example, Esc could be Esc when tapped once, and Caps Lock when tapped twice.
+//! This stage comes after combos, and it replaces events of tap dance keys by events of their
+//! actions.
+//!
+//! Every tap dance key has its own state machine. The dance ends when the key is released and
+//! not pressed again within `term`, or when it is held longer than `term`, or when some other
+//! key is pressed. Action of a tap is pressed for one report, and action of a hold is pressed
+//! until the key is released.
+
+use heapless::Vec; // fixed capacity `std::Vec`
+use typenum::U8 as TapDancesCap; // Maximum number of tap dance keys
+
+use crate::events::{push_warn, EventVec, KeyEvent, TAP_DANCE_MATRIX};
+use crate::process_keys::KeyPos;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum State {
+    Idle,
+    /// Key is pressed for n:th time
+    Pressed { taps: u8, since: u32 },
+    /// Key is released after n:th tap
+    Released { taps: u8, since: u32 },
+    /// Action is pressed until key is released
+    Holding,
+    /// Action is pressed, and it is released in the next report
+    Tapped,
+}
+
+#[derive(Debug)]
+struct TapDance {
+    pos: KeyPos,
+    /// Actions of one, two, three... taps
+    taps: &'static [u32],
+    /// Action when the last tap is held. If None, the tap action is held instead.
+    hold: Option<u32>,
+    state: State,
+    /// Action that is pressed
+    action: u32,
+}
+
+/// Tap dance stage of key events
+#[derive(Debug)]
+pub struct TapDances {
+    /// Maximum time between taps, and minimum time of hold (in microseconds)
+    pub term: u32,
+    keys: Vec<TapDance, TapDancesCap>,
+}
+
+impl TapDances {
+    pub fn new(term: u32) -> TapDances {
+        return TapDances { term, keys: Vec::new() };
+    }
+
+    /// Make key a tap dance key. Returns `Err` if there are too many tap dance keys.
+    /// # Arguments
+    /// * `pos`  Position of the key
+    /// * `taps` Key codes of one, two, three... taps. Must not be empty.
+    /// * `hold` Key code when the last tap is held
+    pub fn add(&mut self, pos: KeyPos, taps: &'static [u32], hold: Option<u32>) -> Result<(), ()> {
+        assert!(!taps.is_empty());
+        let key = TapDance { pos, taps, hold, state: State::Idle, action: 0 };
+        return self.keys.push(key).map_err(|_| ());
+    }
+
+    /// Virtual position of the actions of n:th tap dance key
+    pub fn position_of(idx: usize) -> KeyPos {
+        return KeyPos::new(TAP_DANCE_MATRIX, 0, idx);
+    }
+
+    /// Pass event through tap dance stage. Resulting events are pushed to `out`.
+    pub fn process(&mut self, event: KeyEvent, out: &mut EventVec) {
+        if let Some(idx) = self.keys.iter().position(|k| k.pos == event.pos) {
+            let t = event.time;
+            match (self.keys[idx].state, event.pressed) {
+                (State::Idle, true) => {
+                    self.keys[idx].state = State::Pressed { taps: 1, since: t };
+                }
+                (State::Tapped, true) => {
+                    self.emit(idx, false, t, out);
+                    self.keys[idx].state = State::Pressed { taps: 1, since: t };
+                }
+                (State::Released { taps, .. }, true) => {
+                    self.keys[idx].state = State::Pressed { taps: taps + 1, since: t };
+                }
+                (State::Pressed { taps, .. }, false) => {
+                    if taps as usize >= self.keys[idx].taps.len() {
+                        // There are no actions for more taps
+                        self.tap(idx, taps, t, out);
+                    } else {
+                        self.keys[idx].state = State::Released { taps, since: t };
+                    }
+                }
+                (State::Holding, false) => {
+                    self.emit(idx, false, t, out);
+                    self.keys[idx].state = State::Idle;
+                }
+                // Certainty changes, and releases after dance has ended
+                _ => {}
+            }
+            return;
+        }
+        if event.pressed {
+            // Other key interrupts dances
+            for idx in 0..self.keys.len() {
+                match self.keys[idx].state {
+                    State::Pressed { taps, .. } => self.hold(idx, taps, event.time, out),
+                    State::Released { taps, .. } => self.tap(idx, taps, event.time, out),
+                    _ => {}
+                }
+            }
+        }
+        push_warn(out, event);
+    }
+
+    /// Release tap actions of the previous report, and end dances whose time is up. This should
+    /// be called once per report, before `process`.
+    /// # Arguments
+    /// * `now` Current time (in microseconds)
+    /// * `out` Resulting events are pushed here
+    pub fn update(&mut self, now: u32, out: &mut EventVec) {
+        for idx in 0..self.keys.len() {
+            match self.keys[idx].state {
+                State::Tapped => {
+                    self.emit(idx, false, now, out);
+                    self.keys[idx].state = State::Idle;
+                }
+                State::Pressed { taps, since } if now.wrapping_sub(since) >= self.term => {
+                    self.hold(idx, taps, now, out);
+                }
+                State::Released { taps, since } if now.wrapping_sub(since) >= self.term => {
+                    self.tap(idx, taps, now, out);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn tap(&mut self, idx: usize, taps: u8, time: u32, out: &mut EventVec) {
+        let key = &mut self.keys[idx];
+        key.action = key.taps[usize::min(taps as usize, key.taps.len()) - 1];
+        key.state = State::Tapped;
+        self.emit(idx, true, time, out);
+    }
+
+    fn hold(&mut self, idx: usize, taps: u8, time: u32, out: &mut EventVec) {
+        let key = &mut self.keys[idx];
+        let tap_action = key.taps[usize::min(taps as usize, key.taps.len()) - 1];
+        key.action = key.hold.unwrap_or(tap_action);
+        key.state = State::Holding;
+        self.emit(idx, true, time, out);
+    }
+
+    fn emit(&self, idx: usize, pressed: bool, time: u32, out: &mut EventVec) {
+        let pos = TapDances::position_of(idx);
+        push_warn(out, KeyEvent::generated(pos, self.keys[idx].action, pressed, time));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::testing::{self, event};
+
+    const ESC: KeyPos = KeyPos { matrix: 0, row: 0, col: 0 };
+    const X: KeyPos = KeyPos { matrix: 0, row: 1, col: 0 };
+    const TERM: u32 = 200_000;
+    const KEY_ESC: u32 = 0xF029;
+    const KEY_CAPS_LOCK: u32 = 0xF039;
+    const KEY_CTRL: u32 = 0xE001;
+
+    /// Feed timed events, calling `update` every millisecond like the report stage does, and
+    /// return the resulting (key code, pressed, time) tuples
+    fn run(dances: &mut TapDances, events: &[KeyEvent], end: u32)
+        -> std::vec::Vec<(u32, bool, u32)>
+    {
+        let out = testing::run(dances, events, end,
+                               |d, now, out| d.update(now, out), |d, e, out| d.process(e, out));
+        return out.iter().map(|e| (e.code.into_inner(), e.pressed, e.time)).collect();
+    }
+
+    fn dances(hold: Option<u32>) -> TapDances {
+        let mut dances = TapDances::new(TERM);
+        dances.add(ESC, &[KEY_ESC, KEY_CAPS_LOCK], hold).unwrap();
+        return dances;
+    }
+
+    #[test]
+    fn single_tap_after_term() {
+        let out = run(&mut dances(None), &[event(ESC, true, 0), event(ESC, false, 50_000)],
+                      300_000);
+        assert_eq!(out, [(KEY_ESC, true, 250_000), (KEY_ESC, false, 251_000)]);
+    }
+
+    #[test]
+    fn double_tap_fires_immediately_on_last_action() {
+        let out = run(&mut dances(None), &[
+            event(ESC, true, 0), event(ESC, false, 50_000),
+            event(ESC, true, 100_000), event(ESC, false, 150_000),
+        ], 300_000);
+        assert_eq!(out, [(KEY_CAPS_LOCK, true, 150_000), (KEY_CAPS_LOCK, false, 151_000)]);
+    }
+
+    #[test]
+    fn dance_ends_when_actions_run_out() {
+        let out = run(&mut dances(None), &[
+            event(ESC, true, 0), event(ESC, false, 20_000),
+            event(ESC, true, 40_000), event(ESC, false, 60_000),
+            event(ESC, true, 80_000), event(ESC, false, 100_000),
+        ], 300_000);
+        assert_eq!(out, [
+            (KEY_CAPS_LOCK, true, 60_000), (KEY_CAPS_LOCK, false, 61_000),
+            (KEY_ESC, true, 300_000),
+        ]);
+    }
+
+    #[test]
+    fn hold_after_term() {
+        let out = run(&mut dances(Some(KEY_CTRL)), &[
+            event(ESC, true, 0), event(ESC, false, 500_000),
+        ], 600_000);
+        assert_eq!(out, [(KEY_CTRL, true, 200_000), (KEY_CTRL, false, 500_000)]);
+        // Without hold action, the tap action is held
+        let out = run(&mut dances(None), &[
+            event(ESC, true, 0), event(ESC, false, 500_000),
+        ], 600_000);
+        assert_eq!(out, [(KEY_ESC, true, 200_000), (KEY_ESC, false, 500_000)]);
+    }
+
+    #[test]
+    fn other_key_interrupts_dance() {
+        let x = event(X, true, 0).code.into_inner();
+        // Released key is a tap
+        let out = run(&mut dances(Some(KEY_CTRL)), &[
+            event(ESC, true, 0), event(ESC, false, 30_000), event(X, true, 60_000),
+        ], 100_000);
+        assert_eq!(out, [(KEY_ESC, true, 60_000), (x, true, 60_000), (KEY_ESC, false, 61_000)]);
+        // Pressed key is a hold, e.g. modifier
+        let out = run(&mut dances(Some(KEY_CTRL)), &[
+            event(ESC, true, 0), event(X, true, 60_000), event(ESC, false, 90_000),
+        ], 100_000);
+        assert_eq!(out, [(KEY_CTRL, true, 60_000), (x, true, 60_000), (KEY_CTRL, false, 90_000)]);
+    }
+
+    #[test]
+    fn other_keys_pass_through() {
+        let x = event(X, true, 0).code.into_inner();
+        let out = run(&mut dances(None), &[event(X, true, 0), event(X, false, 10_000)], 20_000);
+        assert_eq!(out, [(x, true, 0), (x, false, 10_000)]);
+    }
+}
@@ -0,0 +1,171 @@
+//! Firmware typematic mode. Normally the host repeats a key that stays pressed in the report,
+//! with the repeat delay and rate of the operating system. In typematic mode the firmware does
+//! the repeating instead: the key is in the report only for one report at a time, and it is sent
+//! again after `delay` and then once in every `interval`. Delay and rate can be set per key, and
+//! some keys can be set not to repeat at all.
+//!
+//! Media keys (e.g. volume up) are not repeated by the firmware. With `Repeat::Off` they are
+//! released right after the press, and otherwise they stay pressed, and the host repeats them.
+
+use heapless::Vec; // fixed capacity `std::Vec`
+
+use crate::ShortVec;
+
+/// Repeat setting of key
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Repeat {
+    /// Key is sent only once
+    Off,
+    /// Key is sent again after `delay`, and then once in every `interval` (in microseconds)
+    On { delay: u32, interval: u32 },
+}
+
+/// Key that is held in typematic mode
+#[derive(Debug)]
+struct HeldKey {
+    key: u8,
+    /// Whether key was in the previous report
+    shown: bool,
+    /// Time when the key is sent next time (in microseconds), or None if it is not repeated
+    next: Option<u32>,
+    /// Whether the key has been sent only once
+    first: bool,
+}
+
+/// Firmware typematic mode
+#[derive(Debug)]
+pub struct Typematic {
+    /// If false, keys are reported as such, and the host repeats them
+    pub enabled: bool,
+    /// Repeat setting of keys that are not listed in `keys`
+    pub default: Repeat,
+    /// Repeat settings of individual keys by their key code
+    keys: &'static [(u32, Repeat)],
+    held: ShortVec<HeldKey>,
+}
+
+impl Typematic {
+    pub fn new(enabled: bool, default: Repeat, keys: &'static [(u32, Repeat)]) -> Typematic {
+        return Typematic { enabled, default, keys, held: Vec::new() };
+    }
+
+    /// Repeat setting of key code
+    pub fn repeat_of(&self, code: u32) -> Repeat {
+        return self.keys.iter()
+            .find(|&&(c, _)| c == code)
+            .map_or(self.default, |&(_, repeat)| repeat);
+    }
+
+    /// Whether typematic mode needs to update the report even if no key changes
+    pub fn is_active(&self) -> bool {
+        return self.enabled && !self.held.is_empty();
+    }
+
+    /// Slots as they are sent in typematic mode. Keys are in the report for one report at a
+    /// time, when it is their time to be sent. If typematic mode is disabled, slots are returned
+    /// as such.
+    /// # Arguments
+    /// * `key_slots` Pressed keys, from `update_slots`
+    /// * `now`       Current time (in microseconds)
+    pub fn apply(&mut self, key_slots: &[Option<u8>; 6], now: u32) -> [Option<u8>; 6] {
+        if !self.enabled {
+            self.held.clear();
+            return *key_slots;
+        }
+        let mut i = 0;
+        while i < self.held.len() {
+            if key_slots.contains(&Some(self.held[i].key)) {
+                i += 1;
+            } else {
+                self.held.swap_remove(i);
+            }
+        }
+        let mut report = [None; 6];
+        for (slot, &key) in report.iter_mut().zip(key_slots.iter()) {
+            let key = match key {
+                Some(key) => key,
+                None => continue,
+            };
+            let repeat = self.repeat_of(key as u32 | 0xF000);
+            let idx = match self.held.iter().position(|h| h.key == key) {
+                Some(idx) => idx,
+                None => {
+                    let new_key = HeldKey { key, shown: false, next: Some(now), first: true };
+                    if self.held.push(new_key).is_err() {
+                        continue;
+                    }
+                    self.held.len() - 1
+                }
+            };
+            let held = &mut self.held[idx];
+            if held.shown {
+                // Release for one report, so that the host sees the next press
+                held.shown = false;
+                continue;
+            }
+            if let Some(next) = held.next.filter(|&next| (now.wrapping_sub(next) as i32) >= 0) {
+                *slot = Some(key);
+                held.shown = true;
+                held.next = match repeat {
+                    Repeat::Off => None,
+                    Repeat::On { delay, .. } if held.first => Some(now.wrapping_add(delay)),
+                    Repeat::On { interval, .. } => Some(next.wrapping_add(interval)),
+                };
+                held.first = false;
+            }
+        }
+        return report;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_A: u8 = 0x04;
+    const KEY_B: u8 = 0x05;
+    const DELAY: u32 = 300_000;
+    const INTERVAL: u32 = 50_000;
+    const REPEATS: &[(u32, Repeat)] = &[(0xF000 | KEY_B as u32, Repeat::Off)];
+
+    fn typematic() -> Typematic {
+        return Typematic::new(true, Repeat::On { delay: DELAY, interval: INTERVAL }, REPEATS);
+    }
+
+    /// Hold `key` from 0 to `end`, applying typematic mode every millisecond, and return the
+    /// times (in milliseconds) when the key is in the report
+    fn hold(typematic: &mut Typematic, key: u8, end: u32) -> std::vec::Vec<u32> {
+        let slots = [Some(key), None, None, None, None, None];
+        return (0..end).step_by(1000)
+            .filter(|&now| typematic.apply(&slots, now)[0] == Some(key))
+            .map(|now| now / 1000)
+            .collect();
+    }
+
+    #[test]
+    fn key_repeats_after_delay_at_interval() {
+        assert_eq!(hold(&mut typematic(), KEY_A, 451_000), [0, 300, 350, 400, 450]);
+    }
+
+    #[test]
+    fn key_without_repeat_is_sent_once() {
+        assert_eq!(hold(&mut typematic(), KEY_B, 1_000_000), [0]);
+    }
+
+    #[test]
+    fn release_stops_repeat() {
+        let mut typematic = typematic();
+        assert_eq!(hold(&mut typematic, KEY_A, 320_000), [0, 300]);
+        assert_eq!(typematic.apply(&[None; 6], 320_000), [None; 6]);
+        assert!(!typematic.is_active());
+        // Next press starts over with the delay
+        assert_eq!(hold(&mut typematic, KEY_A, 301_000), [0, 300]);
+    }
+
+    #[test]
+    fn disabled_mode_passes_slots_through() {
+        let mut typematic = typematic();
+        typematic.enabled = false;
+        assert_eq!(hold(&mut typematic, KEY_A, 10_000).len(), 10);
+    }
+}